@@ -1,14 +1,205 @@
-use std::{cell::RefCell, borrow::Cow};
-use ic_cdk::{caller, call};
+use std::{cell::RefCell, borrow::Cow, collections::{BTreeMap, BTreeSet}, fmt::Display, thread::LocalKey, time::Duration};
+use std::ops::Bound as RangeBound;
+use ic_cdk::call;
+#[cfg(not(test))]
+use ic_cdk::{api::{is_controller, time}, caller};
+// Tests run outside a canister, with a settable clock and caller and a mocked ledger
+#[cfg(test)]
+use tests::{caller, is_controller, ledger_transfer, ledger_transfer_from, time};
+use ic_cdk::api::call::RejectionCode;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable, storable::Bound};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable, storable::Bound};
 use candid::{CandidType, Deserialize, Encode, Decode, Principal};
 use icrc_ledger_types::icrc1::account::{Account, Subaccount};
-use icrc_ledger_types::icrc1::transfer::{BlockIndex, Memo, NumTokens, TransferArg, TransferError};
+use icrc_ledger_types::icrc1::transfer::{BlockIndex, NumTokens, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+#[cfg(not(test))]
+use icrc_ledger_types::{icrc1::transfer::{Memo, TransferArg}, icrc2::transfer_from::TransferFromArgs};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
-const MAX_VALUE_SIZE: u32 = 100; // Increased size for additional data
+// Geo index key: (geohash, (provider ID, location ID))
+type GeoKey = PairKey<String, PairKey<String, String>>;
+// Owner index: (owner, campaign or provider ID)
+type OwnerIndex = StableBTreeMap<PairKey<Principal, String>, (), Memory>;
+// Booking boundary index: (time, booking ID) to (provider ID, location ID)
+type BoundaryIndex = StableBTreeMap<PairKey<u64, String>, PairKey<String, String>, Memory>;
 
+const CAMPAIGN_MEMORY_ID: MemoryId = MemoryId::new(0);
+const PROVIDER_MEMORY_ID: MemoryId = MemoryId::new(1);
+const EARNINGS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const ID_SEQUENCES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const DECODE_FAILURES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const DEPOSIT_MEMORY_ID: MemoryId = MemoryId::new(5);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(6);
+const TOKEN_MEMORY_ID: MemoryId = MemoryId::new(7);
+const PENDING_TRANSFER_MEMORY_ID: MemoryId = MemoryId::new(8);
+const CAMPAIGN_PROVIDER_MEMORY_ID: MemoryId = MemoryId::new(9);
+const BOOKING_MEMORY_ID: MemoryId = MemoryId::new(10);
+const LOCATION_BOOKINGS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ESCROW_MEMORY_ID: MemoryId = MemoryId::new(12);
+const DEVICE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const PLAY_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(14);
+const PLAY_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(15);
+const BILLING_STATE_MEMORY_ID: MemoryId = MemoryId::new(16);
+const BILLING_ACCOUNT_MEMORY_ID: MemoryId = MemoryId::new(17);
+// Memory 18 held the first location index, keyed by location ID alone. It is no longer read.
+// Memory 19 held the first geo index, keyed by geohash and location ID alone. It is no longer read.
+const SEARCH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(20);
+const RECEIPT_MEMORY_ID: MemoryId = MemoryId::new(21);
+const TRANSACTION_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(22);
+const TRANSACTION_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(23);
+const TRANSACTION_INDEX_MEMORY_ID: MemoryId = MemoryId::new(24);
+const RETIRED_DEVICE_MEMORY_ID: MemoryId = MemoryId::new(25);
+const LOCATION_INDEX_MEMORY_ID: MemoryId = MemoryId::new(26);
+const PENDING_REBUILDS_MEMORY_ID: MemoryId = MemoryId::new(27);
+const RECEIPT_OWNER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(28);
+const GEO_INDEX_MEMORY_ID: MemoryId = MemoryId::new(29);
+const CAMPAIGN_OWNER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(30);
+const PROVIDER_OWNER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(31);
+const OWNER_COUNT_MEMORY_ID: MemoryId = MemoryId::new(32);
+const CAMPAIGN_BOOKINGS_MEMORY_ID: MemoryId = MemoryId::new(33);
+const CAMPAIGN_LIFECYCLE_MEMORY_ID: MemoryId = MemoryId::new(34);
+const BOOKING_BOUNDARIES_MEMORY_ID: MemoryId = MemoryId::new(35);
+
+// Campaigns, providers and earnings records grow with their contents (locations, descriptions,
+// image URLs), so they are stored unbounded. Records written while these types were bounded need
+// no rewrite: the registries use the V2 B-tree layout, which prefixes every variable-size value
+// with its length, so a map created with bounded values loads unchanged with unbounded ones.
+
+// Envelope written around every stored value. `version` is the schema version of the Candid
+// encoded `payload`; records written before the envelope existed are treated as version 0.
+#[derive(CandidType, Deserialize)]
+struct VersionedRecord {
+    version: u32,
+    payload: Vec<u8>,
+}
+
+// A value kept in stable memory under a versioned envelope.
+//
+// SCHEMA_VERSION is bumped, and an `upgrade_from` arm added, whenever a stored type changes in a
+// way older payloads cannot be decoded into: a new required field, a changed field type, a
+// removed variant. Adding an `Option` field needs no bump, since Candid decodes a field missing
+// from an older record as None; the same holds for nested types such as Location.
+trait VersionedValue: CandidType + for<'de> Deserialize<'de> + Sized {
+    // Schema version written by this build
+    const SCHEMA_VERSION: u32;
+
+    // Decodes a payload written with an older schema version into the current shape. Types that
+    // never changed shape keep this default, which has no older version to upgrade from.
+    fn upgrade_from(version: u32, _payload: &[u8]) -> Result<Self, String> {
+        Err(unsupported_version::<Self>(version))
+    }
+}
+
+fn unsupported_version<T>(version: u32) -> String {
+    let name = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+    format!("unsupported {} schema version {}", name, version)
+}
+
+fn decode_candid<T: for<'de> Deserialize<'de> + CandidType>(payload: &[u8]) -> Result<T, String> {
+    Decode!(payload, T).map_err(|e| e.to_string())
+}
+
+fn encode_versioned<T: VersionedValue>(value: &T) -> Vec<u8> {
+    let record = VersionedRecord {
+        version: T::SCHEMA_VERSION,
+        payload: Encode!(value).unwrap(),
+    };
+    Encode!(&record).unwrap()
+}
+
+// Schema version a stored value was written with, without decoding the payload
+fn stored_version(bytes: &[u8]) -> u32 {
+    Decode!(bytes, VersionedRecord).map(|record| record.version).unwrap_or(0)
+}
+
+fn decode_versioned<T: VersionedValue>(bytes: &[u8]) -> Result<T, String> {
+    match Decode!(bytes, VersionedRecord) {
+        Ok(record) if record.version == T::SCHEMA_VERSION => decode_candid(&record.payload),
+        Ok(record) if record.version > T::SCHEMA_VERSION => Err(format!(
+            "schema version {} is newer than this build supports ({})",
+            record.version,
+            T::SCHEMA_VERSION
+        )),
+        Ok(record) => T::upgrade_from(record.version, &record.payload),
+        // Not an envelope: a bare record from before values were versioned
+        Err(_) => T::upgrade_from(0, bytes),
+    }
+}
+
+// Implements `Storable` through the versioned envelope. Undecodable records are moved out of
+// their registry by the migration that runs after every upgrade (see `run_migration_batch`), so
+// the panic below is only reachable for records the migration has not visited yet.
+macro_rules! versioned_storable {
+    ($($t:ty),* $(,)?) => {$(
+        impl Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                Cow::Owned(encode_versioned(self))
+            }
+
+            fn into_bytes(self) -> Vec<u8> {
+                encode_versioned(&self)
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                decode_versioned(bytes.as_ref()).unwrap_or_else(|e| {
+                    panic!("Failed to decode {}: {}", stringify!($t), e)
+                })
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    )*};
+}
+
+// Composite key of the stable index maps. Stable structures cannot serialize tuples with unbounded
+// parts such as String, so a pair is written as the length of its first part followed by both
+// parts. Keys compare like the (first, second) tuple.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PairKey<A, B>(A, B);
+
+impl<A: Storable, B: Storable> Storable for PairKey<A, B> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let first = self.0.to_bytes();
+        let mut bytes = (first.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&first);
+        bytes.extend_from_slice(&self.1.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (length, rest) = bytes.split_at(4);
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        let (first, second) = rest.split_at(length);
+        PairKey(A::from_bytes(Cow::Borrowed(first)), B::from_bytes(Cow::Borrowed(second)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Page cursors over string pairs carry the length of the first part, so neither part needs a
+// separator it could contain
+impl Display for PairKey<String, String> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}{}", self.0.len(), self.0, self.1)
+    }
+}
+
+impl std::str::FromStr for PairKey<String, String> {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, ()> {
+        let (length, parts) = text.split_once(':').ok_or(())?;
+        let length: usize = length.parse().map_err(|_| ())?;
+        let first = parts.get(..length).ok_or(())?;
+        Ok(PairKey(first.to_string(), parts[length..].to_string()))
+    }
+}
 
 #[derive(CandidType, Deserialize, Clone)]
 struct Provider {
@@ -16,7 +207,7 @@ struct Provider {
     name: String,
     owner: Principal, // Track who owns this provider
     locations: Vec<Location>,
-    total_earnings: NumTokens, // Track total earnings
+    total_earnings: BTreeMap<String, NumTokens>, // Withdrawable earnings per token symbol
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -25,8 +216,47 @@ struct Location {
     name: String,
     image: String,
     base_fees: NumTokens,
+    token: String, // Symbol of the token base_fees is priced in
     views: u64,
     status: LocationStatus,
+    cpm_rate: Option<NumTokens>, // Price per thousand impressions; replaces base_fees when set
+    coordinates: Option<Coordinates>,
+    address: Option<String>,
+    venue_category: Option<VenueCategory>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct Coordinates {
+    latitude: f64, // Degrees, -90 to 90
+    longitude: f64, // Degrees, -180 to 180
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+enum VenueCategory {
+    Mall,
+    Airport,
+    TransitStation,
+    Street,
+    Stadium,
+    Restaurant,
+    Retail,
+    Office,
+    Other,
+}
+
+// Fields of a location a provider sets; the ID, views and status are managed by the canister
+#[derive(CandidType, Deserialize, Clone)]
+struct LocationInput {
+    name: String,
+    image: String,
+    base_fees: NumTokens,
+    token: String,
+    cpm_rate: Option<NumTokens>,
+    coordinates: Option<Coordinates>,
+    address: Option<String>,
+    venue_category: Option<VenueCategory>,
+    tags: Option<Vec<String>>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -37,8 +267,13 @@ struct Campaign {
     image: Option<String>,
     locations: Option<Vec<Location>>,
     budget: NumTokens,
+    token: String, // Symbol of the token the budget is held in
     owner: Principal, // Track who created this campaign
     status: CampaignStatus,
+    start_time: Option<u64>, // Set by schedule_campaign
+    end_time: Option<u64>,
+    ended_at: Option<u64>, // When the campaign completed or was cancelled
+    refund_block_index: Option<BlockIndex>, // Ledger block of the budget refund made when it was closed
 }
 
 // New struct to track individual campaign-provider earnings
@@ -47,682 +282,6683 @@ struct ProviderEarnings {
     provider_id: String,
     campaign_id: String,
     total_earned: NumTokens,
+    token: String,
     last_withdrawal: Option<u64>, // timestamp
 }
 
-impl Storable for ProviderEarnings {
-    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn into_bytes(self) -> Vec<u8> {
-        Encode!(&self).unwrap()
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
-
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
+// Last issued value of every ID sequence, keyed by sequence name ("campaign", "provider", ...)
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct IdSequences {
+    sequences: BTreeMap<String, u64>,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 enum LocationStatus {
     Active,
     Inactive,
     Booked,
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-enum CampaignStatus {
-    Active,
-    Paused,
+// Error of every endpoint. Ledger and call failures keep what the ledger or the system returned,
+// so clients can tell a retryable failure from a permission or input error.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum SoulboardError {
+    NotFound { kind: String, id: String },
+    Unauthorized(String),
+    InsufficientFunds { available: NumTokens, requested: NumTokens },
+    LedgerError(TransferError), // The ledger rejected a transfer; no funds moved
+    LedgerTransferFromError(TransferFromError), // The ledger rejected an allowance transfer; no funds moved
+    CallFailed { code: RejectionCode, msg: String }, // A call to another canister was rejected
+    TransferPending { transfer_id: u64, msg: String }, // Outcome unknown; the transfer is retried
+    InvalidInput(String),
+    Conflict(String), // The request does not fit the current state
+    Internal(String),
 }
 
-// Implement Storable for Campaign
-impl Storable for Campaign {
-    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn into_bytes(self) -> Vec<u8> {
-        Encode!(&self).unwrap()
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl SoulboardError {
+    fn not_found(kind: &str, id: impl Display) -> Self {
+        SoulboardError::NotFound { kind: kind.to_string(), id: id.to_string() }
     }
-
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
 }
 
-// Implement Storable for Provider
-impl Storable for Provider {
-    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn into_bytes(self) -> Vec<u8> {
-        Encode!(&self).unwrap()
-    }
-
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+impl Display for SoulboardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SoulboardError::NotFound { kind, id } => write!(f, "{} {} not found", kind, id),
+            SoulboardError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            SoulboardError::InsufficientFunds { available, requested } => {
+                write!(f, "Insufficient funds: {} available, {} requested", available, requested)
+            }
+            SoulboardError::LedgerError(e) => write!(f, "Ledger returned an error: {:?}", e),
+            SoulboardError::LedgerTransferFromError(e) => write!(f, "Ledger returned an error: {:?}", e),
+            SoulboardError::CallFailed { code, msg } => write!(f, "Error calling canister: {:?}: {}", code, msg),
+            SoulboardError::TransferPending { transfer_id, msg } => {
+                write!(f, "Transfer {} has an unknown outcome and will be retried: {}", transfer_id, msg)
+            }
+            SoulboardError::InvalidInput(msg) | SoulboardError::Conflict(msg) | SoulboardError::Internal(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
     }
-
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE,
-        is_fixed_size: false,
-    };
-}
-
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
-    // Maps campaign IDs to campaigns - but access will be filtered by owner
-    static CAMPAIGN_REGISTRY: RefCell<StableBTreeMap<String, Campaign, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-        )
-    );
-
-    // Maps provider IDs to providers - these will be publicly visible for marketplace
-    static PROVIDER_REGISTRY: RefCell<StableBTreeMap<String, Provider, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
-        )
-    );
-
-    // Maps earnings key (provider_id:campaign_id) to earnings
-    static EARNINGS_REGISTRY: RefCell<StableBTreeMap<String, ProviderEarnings, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
-        )
-    );
-
-    // Counter for generating unique IDs
-    static CAMPAIGN_COUNTER: RefCell<u64> = RefCell::new(0);
-    static PROVIDER_COUNTER: RefCell<u64> = RefCell::new(0);
 }
 
-// Generate unique campaign ID
-fn generate_campaign_id() -> String {
-    CAMPAIGN_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        format!("campaign_{}", *c)
-    })
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+enum CampaignStatus {
+    Draft, // Created, not scheduled yet
+    Scheduled, // Waiting for its start time
+    Active,
+    Paused,
+    Completed, // Ran until its end time
+    Cancelled, // Closed before its end time
+    Archived,
 }
 
-// Generate unique provider ID
-fn generate_provider_id() -> String {
-    PROVIDER_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        format!("provider_{}", *c)
-    })
+// Schema version 1 shapes, from before balances and fees carried a token. Amounts in these
+// records are in the configured default token.
+#[derive(CandidType, Deserialize)]
+struct ProviderV1 {
+    id: String,
+    name: String,
+    owner: Principal,
+    locations: Vec<LocationV1>,
+    total_earnings: NumTokens,
 }
 
-// Registers a new provider for the calling wallet
-#[ic_cdk::update]
-fn register_provider(name: String, locations: Vec<Location>) -> Result<String, String> {
-    let caller_principal = caller();
-    let provider_id = generate_provider_id();
-    
-    let provider = Provider {
-        id: provider_id.clone(),
-        name,
-        owner: caller_principal,
-        locations,
-        total_earnings: NumTokens::from(0u64),
-    };
-
-    PROVIDER_REGISTRY.with(|registry| {
-        registry.borrow_mut().insert(provider_id.clone(), provider);
-    });
-
-    Ok(provider_id)
+#[derive(CandidType, Deserialize)]
+struct LocationV1 {
+    id: String,
+    name: String,
+    image: String,
+    base_fees: NumTokens,
+    views: u64,
+    status: LocationStatus,
 }
 
-// Creates a new campaign (private to the caller)
-#[ic_cdk::update]
-fn create_campaign(
+#[derive(CandidType, Deserialize)]
+struct CampaignV1 {
+    id: String,
     name: String,
     description: String,
     image: Option<String>,
-    locations: Option<Vec<Location>>,
+    locations: Option<Vec<LocationV1>>,
     budget: NumTokens,
-) -> Result<String, String> {
-    let caller_principal = caller();
-    let campaign_id = generate_campaign_id();
-    
-    let campaign = Campaign {
-        id: campaign_id.clone(),
-        name,
-        description,
-        image,
-        locations,
-        budget,
-        owner: caller_principal,
-        status: CampaignStatus::Active,
-    };
-
-    CAMPAIGN_REGISTRY.with(|registry| {
-        registry.borrow_mut().insert(campaign_id.clone(), campaign);
-    });
+    owner: Principal,
+    status: CampaignStatus,
+}
 
-    Ok(campaign_id)
+#[derive(CandidType, Deserialize)]
+struct ProviderEarningsV1 {
+    provider_id: String,
+    campaign_id: String,
+    total_earned: NumTokens,
+    last_withdrawal: Option<u64>,
 }
 
-/// Transfers some ICP to the specified account.
-async fn icp_transfer(
-    from_subaccount: Option<Subaccount>,
-    to: Account,
-    memo: Option<Vec<u8>>,
+#[derive(CandidType, Deserialize)]
+struct DepositV1 {
+    id: String,
+    campaign_id: String,
+    from: Account,
     amount: NumTokens,
-) -> Result<BlockIndex, String> {
-    // The ID of the ledger canister on the IC mainnet.
-    const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
-    let icp_ledger = Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap();
-    let args = TransferArg {
-        // A "memo" is an arbitrary blob that has no meaning to the ledger, but can be used by
-        // the sender or receiver to attach additional information to the transaction.
-        memo: memo.map(|m| Memo::from(m)),
-        to,
-        amount,
-        // The ledger supports subaccounts. You can pick the subaccount of the caller canister's
-        // account to use for transferring the ICP. If you don't specify a subaccount, the default
-        // subaccount of the caller's account is used.
-        from_subaccount,
-        // The ICP ledger canister charges a fee for transfers, which is deducted from the
-        // sender's account. The fee is fixed to 10_000 e8s (0.0001 ICP). You can specify it here,
-        // to ensure that it hasn't changed, or leave it as None to use the current fee.
-        fee: Some(NumTokens::from(10_000u32)),
-        // The created_at_time is used for deduplication. Not set in this example since it uses
-        // unbounded-wait calls. You should, however, set it if you opt to use bounded-wait
-        // calls, or if you use ingress messages, or if you are worried about bugs in the ICP
-        // ledger.
-        created_at_time: None,
-    };
-
-    // Make the inter-canister call to the ICP ledger
-    match call(icp_ledger, "icrc1_transfer", (args,)).await {
-        Ok((result,)) => {
-            let transfer_result: Result<BlockIndex, TransferError> = result;
-            match transfer_result {
-                Ok(block_index) => Ok(block_index),
-                Err(e) => Err(format!("Ledger returned an error: {:?}", e)),
-            }
-        }
-        Err((code, msg)) => Err(format!("Error calling ledger canister: {:?}: {}", code, msg)),
-    }
+    fee: NumTokens,
+    block_index: BlockIndex,
+    timestamp: u64,
 }
 
-// Helper function to create an account from a principal
-fn principal_to_account(principal: Principal) -> Account {
-    Account {
-        owner: principal,
-        subaccount: None,
+impl LocationV1 {
+    fn upgrade(self, token: &str) -> Location {
+        Location {
+            id: self.id,
+            name: self.name,
+            image: self.image,
+            base_fees: self.base_fees,
+            token: token.to_string(),
+            views: self.views,
+            status: self.status,
+            cpm_rate: None,
+            coordinates: None,
+            address: None,
+            venue_category: None,
+            tags: None,
+        }
     }
 }
 
-// Only the campaign owner can fund their campaign with actual ICP transfer
-#[ic_cdk::update]
-async fn fund_campaign(campaign_id: String, amount: NumTokens) -> Result<String, String> {
-    let caller_principal = caller();
-    let amount_clone = amount.clone();
-    
-    // First, verify the campaign exists and the caller is the owner
-    CAMPAIGN_REGISTRY.with(|registry| {
-        match registry.borrow().get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only fund your own campaigns".to_string());
-                }
-                Ok(())
+// Version 0 of each of these is the bare Candid record written before the envelope existed,
+// which has the same shape as version 1.
+impl VersionedValue for Campaign {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let v1: CampaignV1 = decode_candid(payload)?;
+                let token = ledger_config().token_symbol;
+                Ok(Campaign {
+                    id: v1.id,
+                    name: v1.name,
+                    description: v1.description,
+                    image: v1.image,
+                    locations: v1
+                        .locations
+                        .map(|locations| locations.into_iter().map(|l| l.upgrade(&token)).collect()),
+                    budget: v1.budget,
+                    token,
+                    owner: v1.owner,
+                    status: v1.status,
+                    start_time: None,
+                    end_time: None,
+                    ended_at: None,
+                    refund_block_index: None,
+                })
             }
-            None => Err("Campaign not found".to_string()),
+            _ => Err(unsupported_version::<Self>(version)),
         }
-    })?;
+    }
+}
 
-    // Get this canister's principal as the recipient
-    let canister_principal = ic_cdk::api::id();
-    let canister_account = principal_to_account(canister_principal);
-    
-    // Transfer ICP from the caller to this canister
-    let transfer_memo = format!("Fund campaign: {}", campaign_id).into_bytes();
-    let transfer_amount = amount; // Create a copy for the transfer
-    match icp_transfer(
-        None, // from_subaccount - uses caller's default
-        canister_account, // to - this canister
-        Some(transfer_memo),
-        transfer_amount,
-    ).await {
-        Ok(block_index) => {
-            // If transfer successful, update the campaign budget
-            CAMPAIGN_REGISTRY.with(|registry| {
-                let mut registry_borrow = registry.borrow_mut();
-                if let Some(mut campaign) = registry_borrow.get(&campaign_id) {
-                    campaign.budget += amount_clone;
-                    registry_borrow.insert(campaign_id.clone(), campaign);
+impl VersionedValue for Provider {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let v1: ProviderV1 = decode_candid(payload)?;
+                let token = ledger_config().token_symbol;
+                let mut total_earnings = BTreeMap::new();
+                if v1.total_earnings > 0u64 {
+                    total_earnings.insert(token.clone(), v1.total_earnings);
                 }
-            });
-            
-            Ok(format!("Campaign funded successfully. Transfer block index: {}", block_index))
+                Ok(Provider {
+                    id: v1.id,
+                    name: v1.name,
+                    owner: v1.owner,
+                    locations: v1.locations.into_iter().map(|l| l.upgrade(&token)).collect(),
+                    total_earnings,
+                })
+            }
+            _ => Err(unsupported_version::<Self>(version)),
         }
-        Err(e) => Err(format!("Failed to transfer ICP: {}", e)),
     }
 }
 
-// Provider can withdraw their earnings with actual ICP transfer
-#[ic_cdk::update]
-async fn withdraw_provider_earnings(provider_id: String, amount: NumTokens) -> Result<String, String> {
-    let caller_principal = caller();
-    let amount_clone = amount.clone(); // Clone for later use
-    
-    // Verify the provider exists and the caller is the owner
-    PROVIDER_REGISTRY.with(|registry| {
-        match registry.borrow().get(&provider_id) {
-            Some(provider) => {
-                if provider.owner != caller_principal {
-                    return Err("Unauthorized: You can only withdraw from your own provider account".to_string());
-                }
-                if provider.total_earnings < amount_clone {
-                    return Err("Insufficient earnings to withdraw".to_string());
-                }
-                Ok(())
+impl VersionedValue for ProviderEarnings {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let v1: ProviderEarningsV1 = decode_candid(payload)?;
+                Ok(ProviderEarnings {
+                    provider_id: v1.provider_id,
+                    campaign_id: v1.campaign_id,
+                    total_earned: v1.total_earned,
+                    token: ledger_config().token_symbol,
+                    last_withdrawal: v1.last_withdrawal,
+                })
             }
-            None => Err("Provider not found".to_string()),
+            _ => Err(unsupported_version::<Self>(version)),
         }
-    })?;
+    }
+}
 
-    // Create account for the provider owner
-    let provider_account = principal_to_account(caller_principal);
-    
-    // Transfer ICP from this canister to the provider
-    let transfer_memo = format!("Provider withdrawal: {}", provider_id).into_bytes();
-    match icp_transfer(
-        None, // from_subaccount - uses canister's default
-        provider_account, // to - provider's account
-        Some(transfer_memo),
-        amount,
-    ).await {
-        Ok(block_index) => {
-            // If transfer successful, update the provider's earnings
-            PROVIDER_REGISTRY.with(|registry| {
-                let mut registry_borrow = registry.borrow_mut();
-                if let Some(mut provider) = registry_borrow.get(&provider_id) {
-                    provider.total_earnings -= amount_clone;
-                    registry_borrow.insert(provider_id.clone(), provider);
-                }
-            });
-            
-            Ok(format!("Withdrawal successful. Transfer block index: {}", block_index))
+impl VersionedValue for IdSequences {
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => decode_candid(payload),
+            _ => Err(unsupported_version::<Self>(version)),
         }
-        Err(e) => Err(format!("Failed to transfer ICP: {}", e)),
     }
 }
 
-// Function to add earnings to a provider (called when campaign pays provider)
-#[ic_cdk::update]
-async fn pay_provider(campaign_id: String, provider_id: String, amount: NumTokens) -> Result<String, String> {
-    let caller_principal = caller();
-    let amount_clone1 = amount.clone();
-    let amount_clone2 = amount.clone();
-    let amount_clone3 = amount.clone();
-    
-    // Verify the campaign exists and the caller is the owner
-    CAMPAIGN_REGISTRY.with(|registry| {
-        match registry.borrow().get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only pay from your own campaigns".to_string());
-                }
-                if campaign.budget < amount_clone1 {
-                    return Err("Insufficient campaign budget".to_string());
-                }
-                Ok(())
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })?;
+// Default ledger of the canister: campaigns created without a token and records written before
+// multi-token support use it. Passed as the init and post_upgrade argument; an upgrade without an
+// argument keeps the stored configuration. The default token is always in the token registry.
+#[derive(CandidType, Deserialize, Clone)]
+struct CanisterConfig {
+    ledger_canister_id: Principal,
+    ledger_fee: NumTokens, // transfer fee in the token's smallest unit
+    ledger_decimals: u8,
+    token_symbol: String,
+}
 
-    // Verify the provider exists
-    PROVIDER_REGISTRY.with(|registry| {
-        match registry.borrow().get(&provider_id) {
-            Some(_) => Ok(()),
-            None => Err("Provider not found".to_string()),
-        }
-    })?;
-
-    // Update campaign budget
-    CAMPAIGN_REGISTRY.with(|registry| {
-        let mut registry_borrow = registry.borrow_mut();
-        if let Some(mut campaign) = registry_borrow.get(&campaign_id) {
-            campaign.budget -= amount_clone2;
-            registry_borrow.insert(campaign_id.clone(), campaign);
-        }
-    });
+// The ID of the ledger canister on the IC mainnet.
+const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
 
-    // Update provider earnings
-    PROVIDER_REGISTRY.with(|registry| {
-        let mut registry_borrow = registry.borrow_mut();
-        if let Some(mut provider) = registry_borrow.get(&provider_id) {
-            provider.total_earnings += amount_clone3;
-            registry_borrow.insert(provider_id.clone(), provider);
+impl Default for CanisterConfig {
+    // The ICP ledger on the IC mainnet
+    fn default() -> Self {
+        Self {
+            ledger_canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
+            ledger_fee: NumTokens::from(10_000u64), // 0.0001 ICP
+            ledger_decimals: 8,
+            token_symbol: "ICP".to_string(),
         }
-    });
+    }
+}
 
-    // Update or create earnings record
-    let earnings_key = format!("{}:{}", provider_id, campaign_id);
-    EARNINGS_REGISTRY.with(|registry| {
-        let mut registry_borrow = registry.borrow_mut();
-        match registry_borrow.get(&earnings_key) {
-            Some(mut earnings) => {
-                earnings.total_earned += amount.clone();
-                registry_borrow.insert(earnings_key, earnings);
-            }
-            None => {
-                let new_earnings = ProviderEarnings {
-                    provider_id: provider_id.clone(),
-                    campaign_id: campaign_id.clone(),
-                    total_earned: amount.clone(),
-                    last_withdrawal: None,
-                };
-                registry_borrow.insert(earnings_key, new_earnings);
-            }
-        }
-    });
+impl VersionedValue for CanisterConfig {
+    const SCHEMA_VERSION: u32 = 1;
+}
 
-    Ok(format!("Payment of {} tokens made to provider {}", amount, provider_id))
+// An ICRC-1 ledger that campaigns and location fees can be denominated in
+#[derive(CandidType, Deserialize, Clone)]
+struct TokenConfig {
+    symbol: String,
+    ledger_canister_id: Principal,
+    fee: NumTokens, // transfer fee in the token's smallest unit
+    decimals: u8,
 }
 
-// Only the campaign owner can withdraw funds from their campaign budget (emergency/unused funds)
-#[ic_cdk::update]
-async fn withdraw_campaign_funds(campaign_id: String, amount: NumTokens) -> Result<String, String> {
-    let caller_principal = caller();
-    let amount_clone = amount.clone();
-    
-    // Verify the campaign exists and the caller is the owner, then update budget
-    CAMPAIGN_REGISTRY.with(|registry| {
-        let mut registry_borrow = registry.borrow_mut();
-        
-        match registry_borrow.get(&campaign_id) {
-            Some(mut campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only withdraw from your own campaigns".to_string());
-                }
-                
-                if campaign.budget < amount_clone {
-                    return Err("Insufficient funds".to_string());
-                }
-                
-                campaign.budget -= amount_clone.clone();
-                registry_borrow.insert(campaign_id.clone(), campaign);
-                Ok(())
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })?;
+impl VersionedValue for TokenConfig {
+    const SCHEMA_VERSION: u32 = 1;
+}
 
-    // Create account for the campaign owner
-    let owner_account = principal_to_account(caller_principal);
-    
-    // Transfer ICP from this canister to the campaign owner
-    let transfer_memo = format!("Campaign withdrawal: {}", campaign_id).into_bytes();
-    match icp_transfer(
-        None, // from_subaccount - uses canister's default
-        owner_account, // to - campaign owner's account
-        Some(transfer_memo),
-        amount,
-    ).await {
-        Ok(block_index) => {
-            Ok(format!("Campaign funds withdrawal successful. Transfer block index: {}", block_index))
-        }
-        Err(e) => {
-            // Rollback the budget change if transfer failed
-            CAMPAIGN_REGISTRY.with(|registry| {
-                let mut registry_borrow = registry.borrow_mut();
-                if let Some(mut campaign) = registry_borrow.get(&campaign_id) {
-                    campaign.budget += amount_clone;
-                    registry_borrow.insert(campaign_id, campaign);
-                }
-            });
-            Err(format!("Failed to transfer ICP: {}", e))
+impl From<CanisterConfig> for TokenConfig {
+    fn from(config: CanisterConfig) -> Self {
+        Self {
+            symbol: config.token_symbol,
+            ledger_canister_id: config.ledger_canister_id,
+            fee: config.ledger_fee,
+            decimals: config.ledger_decimals,
         }
     }
 }
 
-// Only the campaign owner can close their campaign
-#[ic_cdk::update]
-fn close_campaign(campaign_id: String) -> Result<(), String> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        let mut registry_borrow = registry.borrow_mut();
-        
-        match registry_borrow.get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only close your own campaigns".to_string());
-                }
-                
-                registry_borrow.remove(&campaign_id);
-                Ok(())
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })
+// A ledger transfer credited to a campaign's budget
+#[derive(CandidType, Deserialize, Clone)]
+struct Deposit {
+    id: String,
+    campaign_id: String,
+    from: Account, // advertiser account, or the campaign's deposit account for swept transfers
+    amount: NumTokens, // amount credited to the budget
+    token: String,
+    fee: NumTokens, // ledger fee paid on top of `amount`
+    block_index: BlockIndex,
+    timestamp: u64,
 }
 
-// Get provider earnings (only provider owner can see)
-#[ic_cdk::query]
-fn get_provider_earnings(provider_id: String) -> Result<NumTokens, String> {
-    let caller_principal = caller();
-    
-    PROVIDER_REGISTRY.with(|registry| {
-        match registry.borrow().get(&provider_id) {
-            Some(provider) => {
-                if provider.owner != caller_principal {
-                    return Err("Unauthorized: You can only view your own provider earnings".to_string());
-                }
-                Ok(provider.total_earnings)
+impl VersionedValue for Deposit {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let v1: DepositV1 = decode_candid(payload)?;
+                Ok(Deposit {
+                    id: v1.id,
+                    campaign_id: v1.campaign_id,
+                    from: v1.from,
+                    amount: v1.amount,
+                    token: ledger_config().token_symbol,
+                    fee: v1.fee,
+                    block_index: v1.block_index,
+                    timestamp: v1.timestamp,
+                })
             }
-            None => Err("Provider not found".to_string()),
+            _ => Err(unsupported_version::<Self>(version)),
         }
-    })
+    }
 }
 
-// Get detailed earnings breakdown for a provider
-#[ic_cdk::query]
-fn get_provider_earnings_breakdown(provider_id: String) -> Result<Vec<ProviderEarnings>, String> {
-    let caller_principal = caller();
-    
-    // Verify provider ownership
-    PROVIDER_REGISTRY.with(|registry| {
-        match registry.borrow().get(&provider_id) {
-            Some(provider) => {
-                if provider.owner != caller_principal {
-                    return Err("Unauthorized: You can only view your own provider earnings".to_string());
-                }
-                Ok(())
-            }
-            None => return Err("Provider not found".to_string()),
-        }
-    })?;
+// What a journaled ledger transfer is for, and thereby what settling it does
+#[derive(CandidType, Deserialize, Clone)]
+enum TransferPurpose {
+    CampaignFunding { campaign_id: String },
+    DepositSweep { campaign_id: String },
+    ProviderWithdrawal { provider_id: String },
+    CampaignWithdrawal { campaign_id: String },
+    CampaignCloseRefund { campaign_id: String },
+}
 
-    // Get all earnings for this provider
-    EARNINGS_REGISTRY.with(|registry| {
-        Ok(registry
-            .borrow()
-            .iter()
-            .filter_map(|entry| {
-                let earnings = entry.value();
-                if earnings.provider_id == provider_id {
-                    Some(earnings)
-                } else {
-                    None
-                }
-            })
-            .collect())
-    })
+// Where the tokens of a journaled transfer come from
+#[derive(CandidType, Deserialize, Clone)]
+enum TransferSource {
+    Canister(Option<Subaccount>), // a subaccount of this canister, sent with icrc1_transfer
+    Allowance(Account), // an account that approved this canister, pulled with icrc2_transfer_from
 }
 
-// Get campaign balance (only campaign owner can see)
-#[ic_cdk::query]
-fn get_campaign_balance(campaign_id: String) -> Result<NumTokens, String> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        match registry.borrow().get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only view your own campaign balance".to_string());
-                }
-                Ok(campaign.budget)
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+enum PendingTransferStatus {
+    Pending, // journaled, first attempt not answered yet
+    Uncertain, // an attempt ended without a definite answer from the ledger
 }
 
-#[ic_cdk::update]
-fn add_provider(campaign_id: String, _provider_id: String) -> Result<(), String> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        let registry_borrow = registry.borrow();
-        
-        match registry_borrow.get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only modify your own campaigns".to_string());
-                }
-                
-                // Here you would add logic to associate the provider with the campaign
-                // This might involve updating the campaign's locations or maintaining
-                // a separate mapping of campaign-provider relationships
-                
-                Ok(())
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })
+// A ledger transfer whose outcome has not been applied yet. Entries are written before the
+// transfer is sent and removed once its outcome is settled, so the journal only ever holds
+// transfers that are in flight or need a retry.
+#[derive(CandidType, Deserialize, Clone)]
+struct PendingTransfer {
+    id: u64,
+    purpose: TransferPurpose,
+    token: String,
+    source: TransferSource,
+    to: Account,
+    amount: NumTokens,
+    fee: NumTokens,
+    memo: Vec<u8>,
+    created_at_time: u64,
+    status: PendingTransferStatus,
+    // Taken from a campaign budget or provider earnings for a payout: the amount plus the ledger
+    // fee. None for incoming transfers, and for payouts journaled before the fee was charged to
+    // the paying balance, which only took the amount.
+    debited: Option<NumTokens>,
+    attempts: u32,
+    last_error: Option<String>,
+    updated_at: u64,
 }
 
-#[ic_cdk::update]
-fn remove_provider(campaign_id: String, _provider_id: String) -> Result<(), String> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        let registry_borrow = registry.borrow();
-        
-        match registry_borrow.get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only modify your own campaigns".to_string());
-                }
-                
-                // Logic to remove provider association
-                Ok(())
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })
+impl VersionedValue for PendingTransfer {
+    const SCHEMA_VERSION: u32 = 1;
 }
 
-// Returns only campaigns created by the caller (PRIVATE)
-#[ic_cdk::query]
-fn get_my_campaigns() -> Vec<Campaign> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        registry
-            .borrow()
-            .iter()
-            .filter_map(|entry| {
-                let campaign = entry.value();
-                if campaign.owner == caller_principal {
-                    Some(campaign)
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+// A provider linked to a campaign. `location_ids` are the provider's locations the campaign
+// runs on; empty when the campaign is linked to the provider as a whole.
+#[derive(CandidType, Deserialize, Clone)]
+struct CampaignProviderLink {
+    campaign_id: String,
+    provider_id: String,
+    location_ids: Vec<String>,
+    linked_at: u64,
 }
 
-#[ic_cdk::query]
-fn get_my_providers() -> Vec<Provider> {
-    let caller_principal = caller();
-    
-    PROVIDER_REGISTRY.with(|registry| {
-        registry
-            .borrow()
-            .iter()
-            .filter_map(|entry| {
-                let provider = entry.value();
-                if provider.owner == caller_principal {
-                    Some(provider)
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+impl VersionedValue for CampaignProviderLink {
+    const SCHEMA_VERSION: u32 = 1;
 }
 
-#[ic_cdk::query]
-fn get_all_providers() -> Vec<Provider> {
-    PROVIDER_REGISTRY.with(|registry| {
-        registry
-            .borrow()
-            .iter()
-            .map(|entry| entry.value())
-            .collect()
-    })
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+enum BookingStatus {
+    Requested, // Costs nothing up front, so it waits for the provider to accept it
+    Confirmed,
+    Cancelled,
 }
 
-#[ic_cdk::query]
-fn get_all_locations() -> Vec<Location> {
-    PROVIDER_REGISTRY.with(|registry| {
-        registry
-            .borrow()
-            .iter()
-            .flat_map(|entry| entry.value().locations.clone())
-            .collect()
-    })
+// A campaign booked on a provider location for [start_time, end_time), in nanoseconds since the
+// epoch. Both bounds are aligned to SLOT_DURATION_NANOS.
+#[derive(CandidType, Deserialize, Clone)]
+struct Booking {
+    id: String,
+    campaign_id: String,
+    provider_id: String,
+    location_id: String,
+    start_time: u64,
+    end_time: u64,
+    status: BookingStatus,
+    created_at: u64,
 }
 
-// Get providers for a specific campaign (only if caller owns the campaign)
-#[ic_cdk::query]
-fn get_providers_for_campaign(campaign_id: String) -> Result<Vec<Provider>, String> {
-    let caller_principal = caller();
-    
-    CAMPAIGN_REGISTRY.with(|registry| {
-        match registry.borrow().get(&campaign_id) {
-            Some(campaign) => {
-                if campaign.owner != caller_principal {
-                    return Err("Unauthorized: You can only view your own campaigns".to_string());
-                }
-                
-                // Here you would return the providers associated with this campaign
-                // This requires additional logic to track campaign-provider relationships
-                Ok(Vec::new()) // Placeholder
-            }
-            None => Err("Campaign not found".to_string()),
-        }
-    })
+impl VersionedValue for Booking {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+// A free time range of a location, in nanoseconds since the epoch
+#[derive(CandidType, Deserialize, Clone)]
+struct TimeRange {
+    start_time: u64,
+    end_time: u64,
+}
+
+// Cost of a booking held back from the campaign budget until its slots are delivered. Slots are
+// identified by their start time; every slot of the booking is either still escrowed, delivered
+// (released to the provider) or refunded to the campaign.
+#[derive(CandidType, Deserialize, Clone)]
+struct BookingEscrow {
+    booking_id: String,
+    campaign_id: String,
+    provider_id: String,
+    token: String,
+    slot_fee: NumTokens, // the location's base_fees when the booking was made
+    // The location's cpm_rate when the booking was made; its plays are billed at this rate.
+    // Absent in escrows made before the rate was recorded.
+    cpm_rate: Option<NumTokens>,
+    delivered_slots: Vec<u64>,
+    played_slots: Option<Vec<u64>>, // slots with at least one signed play of the booking's campaign
+    refunded_slots: Vec<u64>,
+    escrowed: NumTokens,
+    released: NumTokens,
+    refunded: NumTokens,
 }
 
+impl VersionedValue for BookingEscrow {
+    const SCHEMA_VERSION: u32 = 1;
+}
 
+// A display device registered by a provider for one of its locations. Devices report plays
+// under their own principal and sign every play with their Ed25519 key.
+#[derive(CandidType, Deserialize, Clone)]
+struct Device {
+    principal: Principal,
+    provider_id: String,
+    location_id: String,
+    registered_at: u64,
+    public_key: Option<Vec<u8>>, // Ed25519 public key; plays are rejected until one is set
+    last_counter: u64, // counter of the last play with a valid attestation
+    invalid_attestations: u64, // plays with a bad signature or a replayed counter
+}
 
-ic_cdk::export_candid!();
+// Schema version 1 shape, from before devices signed their plays
+#[derive(CandidType, Deserialize)]
+struct DeviceV1 {
+    principal: Principal,
+    provider_id: String,
+    location_id: String,
+    registered_at: u64,
+}
+
+impl VersionedValue for Device {
+    const SCHEMA_VERSION: u32 = 2;
+
+    fn upgrade_from(version: u32, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            1 => {
+                let v1: DeviceV1 = decode_candid(payload)?;
+                Ok(Device {
+                    principal: v1.principal,
+                    provider_id: v1.provider_id,
+                    location_id: v1.location_id,
+                    registered_at: v1.registered_at,
+                    public_key: None,
+                    last_counter: 0,
+                    invalid_attestations: 0,
+                })
+            }
+            _ => Err(unsupported_version::<Self>(version)),
+        }
+    }
+}
 
+// A play of a campaign creative as reported by a device. `signature` is the device's Ed25519
+// signature over `play_signing_message`; `counter` has to be higher than the counter of every
+// play the device reported before.
+#[derive(CandidType, Deserialize, Clone)]
+struct PlayEvent {
+    campaign_id: String,
+    creative_hash: String,
+    timestamp: u64, // when the play started, in nanoseconds since the epoch
+    duration_ms: u64,
+    counter: u64,
+    signature: Vec<u8>,
+}
+
+// An accepted play, kept in the append-only play log for auditing
+#[derive(CandidType, Deserialize, Clone)]
+struct PlayRecord {
+    device: Principal,
+    provider_id: String,
+    location_id: String,
+    booking_id: String,
+    campaign_id: String,
+    creative_hash: String,
+    timestamp: u64,
+    duration_ms: u64,
+    reported_at: u64,
+    // Attestation of the play; absent in plays logged before devices signed them
+    counter: Option<u64>,
+    signature: Option<Vec<u8>>,
+}
+
+impl VersionedValue for PlayRecord {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+// A play event of a report that was not accepted, by its position in the report
+#[derive(CandidType, Deserialize, Clone)]
+struct RejectedPlay {
+    index: u64,
+    reason: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct PlayReportResult {
+    accepted: u64,
+    rejected: Vec<RejectedPlay>,
+}
+
+// Position of the billing engine in the play log
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct BillingState {
+    next_play: u64, // index of the first play log entry not billed yet
+}
+
+impl VersionedValue for BillingState {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+// Impressions of a campaign at a CPM-priced location and what they were charged. A single
+// impression costs cpm_rate / 1000, which is rarely a whole token unit; `carry` holds the
+// thousandths of a unit owed but not charged yet, so no fraction is lost or rounded up.
+#[derive(CandidType, Deserialize, Clone)]
+struct BillingAccount {
+    campaign_id: String,
+    provider_id: String,
+    location_id: String,
+    token: String,
+    impressions: u64, // impressions charged to the campaign
+    unpaid_impressions: u64, // impressions played after the campaign budget ran out
+    charged: NumTokens,
+    carry: NumTokens,
+}
+
+impl VersionedValue for BillingAccount {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ReceiptKind {
+    CampaignFunding,
+    DepositSweep,
+    ProviderWithdrawal,
+    CampaignWithdrawal,
+    CampaignCloseRefund,
+    ProviderPayment, // Budget moved to a provider's earnings by the former pay_provider endpoint
+}
+
+// Record of a completed balance movement, kept so it can be looked up later. Ledger transfers use
+// their journal ID as transaction ID; movements inside the canister draw one from the same
+// sequence.
+#[derive(CandidType, Deserialize, Clone)]
+struct Receipt {
+    transaction_id: u64,
+    kind: ReceiptKind,
+    owner: Principal, // Owner of the campaign or provider whose balance changed
+    campaign_id: Option<String>,
+    provider_id: Option<String>,
+    token: String,
+    amount: NumTokens,
+    fee: NumTokens, // Ledger fee of the transfer; zero inside the canister
+    balance: NumTokens, // Campaign budget or provider earnings after the movement
+    block_index: Option<BlockIndex>, // None for movements inside the canister
+    timestamp: u64,
+}
+
+impl VersionedValue for Receipt {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+// Where an internal balance movement takes funds from or puts them
+#[derive(CandidType, Deserialize, Clone)]
+enum TransactionAccount {
+    External(Account), // A ledger account outside the canister's bookkeeping
+    Campaign(String), // Campaign budget
+    Provider(String), // Provider earnings
+    Escrow(String), // Escrow of a booking, by booking ID
+    Transfer(u64), // Funds held for an outgoing journaled transfer until it settles
+    Canister, // The canister's own funds
+    LedgerFees, // Fees paid to the ledger
+}
+
+impl TransactionAccount {
+    fn key(&self) -> String {
+        match self {
+            TransactionAccount::External(account) => format!("external:{}", account),
+            TransactionAccount::Campaign(campaign_id) => format!("campaign:{}", campaign_id),
+            TransactionAccount::Provider(provider_id) => format!("provider:{}", provider_id),
+            TransactionAccount::Escrow(booking_id) => format!("escrow:{}", booking_id),
+            TransactionAccount::Transfer(transfer_id) => format!("transfer:{}", transfer_id),
+            TransactionAccount::Canister => "canister".to_string(),
+            TransactionAccount::LedgerFees => "ledger_fees".to_string(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+enum TransactionKind {
+    OpeningBalance, // Balance that existed when the transaction log was introduced
+    Deposit,
+    Escrow,
+    Release,
+    Refund,
+    Payment,
+    Withdrawal,
+    Fee,
+}
+
+// One entry of the append-only transaction log
+#[derive(CandidType, Deserialize, Clone)]
+struct TransactionRecord {
+    id: u64, // Position in the log
+    kind: TransactionKind,
+    from: TransactionAccount,
+    to: TransactionAccount,
+    amount: NumTokens,
+    token: String,
+    memo: String,
+    caller: Principal, // The canister itself for timer-driven movements
+    timestamp: u64,
+    block_index: Option<BlockIndex>,
+    campaign_id: Option<String>, // Campaign the movement belongs to, if any
+}
+
+impl VersionedValue for TransactionRecord {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+// A balance recomputed from the transaction log next to the stored one
+#[derive(CandidType, Deserialize, Clone)]
+struct BalanceCheck {
+    token: String,
+    credited: NumTokens,
+    debited: NumTokens,
+    logged_balance: Option<NumTokens>, // None if the log debits more than it credits
+    stored_balance: NumTokens,
+    consistent: bool,
+}
+
+// A stored record that could not be decoded after an upgrade. The migration moves it out of its
+// registry, raw bytes included, so it can be inspected and repaired without trapping reads.
+#[derive(CandidType, Deserialize, Clone)]
+struct DecodeFailure {
+    registry: String,
+    key: String,
+    stored_version: u32,
+    error: String,
+    detected_at: u64,
+    raw: Vec<u8>,
+}
+
+impl VersionedValue for DecodeFailure {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+versioned_storable!(
+    Campaign,
+    Provider,
+    ProviderEarnings,
+    IdSequences,
+    CanisterConfig,
+    TokenConfig,
+    Deposit,
+    PendingTransfer,
+    CampaignProviderLink,
+    Booking,
+    BookingEscrow,
+    Device,
+    PlayRecord,
+    BillingState,
+    BillingAccount,
+    Receipt,
+    TransactionRecord,
+    DecodeFailure,
+    PendingRebuilds,
+);
+
+// Progress of the migration started by the last upgrade
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct MigrationStatus {
+    running: bool,
+    current_registry: Option<String>,
+    migrated: u64,
+    quarantined: u64,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+}
+
+// Derived state the rebuild passes after the migration still have to fill in. Decided when an
+// upgrade starts and kept in stable memory, so an upgrade during the passes does not lose them.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct PendingRebuilds {
+    location_index: bool,
+    search_index: bool,
+    opening_balances: bool, // balances held before the transaction log existed
+    receipt_index: bool,
+    geo_index: bool,
+    owner_index: bool,
+    campaign_bookings: bool,
+    lifecycle_index: bool,
+    booking_boundaries: bool,
+}
+
+impl VersionedValue for PendingRebuilds {
+    const SCHEMA_VERSION: u32 = 1;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // Maps campaign IDs to campaigns - but access will be filtered by owner
+    static CAMPAIGN_REGISTRY: RefCell<StableBTreeMap<String, Campaign, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CAMPAIGN_MEMORY_ID)),
+        )
+    );
+
+    // Maps provider IDs to providers - these will be publicly visible for marketplace
+    static PROVIDER_REGISTRY: RefCell<StableBTreeMap<String, Provider, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PROVIDER_MEMORY_ID)),
+        )
+    );
+
+    // Maps earnings key (provider_id:campaign_id) to earnings
+    static EARNINGS_REGISTRY: RefCell<StableBTreeMap<String, ProviderEarnings, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EARNINGS_MEMORY_ID)),
+        )
+    );
+
+    // Counters for generating unique IDs, kept in stable memory so they survive upgrades
+    static ID_SEQUENCES: RefCell<StableCell<IdSequences, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ID_SEQUENCES_MEMORY_ID)),
+            IdSequences::default(),
+        )
+    );
+
+    // Maps deposit IDs to the ledger transfers credited to campaigns
+    static DEPOSIT_REGISTRY: RefCell<StableBTreeMap<String, Deposit, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEPOSIT_MEMORY_ID)),
+        )
+    );
+
+    // Receipts of completed balance movements, keyed by transaction ID
+    static RECEIPTS: RefCell<StableBTreeMap<u64, Receipt, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RECEIPT_MEMORY_ID)),
+        )
+    );
+
+    // Campaigns and providers keyed by (owner, ID), so the owner listings only read the caller's
+    // own records. Owners never change and neither record is deleted, so entries are only added.
+    static CAMPAIGN_OWNER_INDEX: RefCell<OwnerIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CAMPAIGN_OWNER_INDEX_MEMORY_ID)),
+        )
+    );
+    static PROVIDER_OWNER_INDEX: RefCell<OwnerIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PROVIDER_OWNER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // Number of entries per owner in the owner indexes, keyed by (owner, CAMPAIGN_SEQUENCE or
+    // PROVIDER_SEQUENCE)
+    static OWNER_COUNTS: RefCell<StableBTreeMap<PairKey<Principal, String>, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OWNER_COUNT_MEMORY_ID)),
+        )
+    );
+
+    // Receipts keyed by (owner, transaction ID), so an owner's receipts are read without scanning
+    // everyone else's
+    static RECEIPT_OWNER_INDEX: RefCell<StableBTreeMap<PairKey<Principal, u64>, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RECEIPT_OWNER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // Append-only log of every internal balance movement
+    static TRANSACTION_LOG: RefCell<StableLog<TransactionRecord, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTION_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTION_LOG_DATA_MEMORY_ID)),
+        )
+    );
+
+    // Transaction log positions by "account/<account>" and "campaign/<campaign ID>"
+    static TRANSACTION_INDEX: RefCell<StableBTreeMap<PairKey<String, u64>, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTION_INDEX_MEMORY_ID)),
+        )
+    );
+
+    static CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CONFIG_MEMORY_ID)),
+            CanisterConfig::default(),
+        )
+    );
+
+    // Maps token symbols to the ledgers campaigns may be denominated in
+    static TOKEN_REGISTRY: RefCell<StableBTreeMap<String, TokenConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_MEMORY_ID)),
+        )
+    );
+
+    // Journal of ledger transfers whose outcome has not been applied yet, keyed by transfer ID
+    static PENDING_TRANSFERS: RefCell<StableBTreeMap<u64, PendingTransfer, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_TRANSFER_MEMORY_ID)),
+        )
+    );
+
+    // Maps "<campaign_id>:<provider_id>" to the providers and locations linked to campaigns
+    static CAMPAIGN_PROVIDER_LINKS: RefCell<StableBTreeMap<String, CampaignProviderLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CAMPAIGN_PROVIDER_MEMORY_ID)),
+        )
+    );
+
+    // Maps booking IDs to bookings, cancelled ones included
+    static BOOKING_REGISTRY: RefCell<StableBTreeMap<String, Booking, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BOOKING_MEMORY_ID)),
+        )
+    );
+
+    // Bookings of every status per campaign, keyed by (campaign ID, booking ID)
+    static CAMPAIGN_BOOKINGS: RefCell<StableBTreeMap<PairKey<String, String>, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CAMPAIGN_BOOKINGS_MEMORY_ID)),
+        )
+    );
+
+    // Campaigns with a lifecycle transition ahead of them, keyed by (time it is due, campaign ID)
+    static CAMPAIGN_LIFECYCLE_INDEX: RefCell<StableBTreeMap<PairKey<u64, String>, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CAMPAIGN_LIFECYCLE_MEMORY_ID)),
+        )
+    );
+
+    // Upcoming starts and ends of confirmed bookings, keyed by (time, booking ID) and mapped to
+    // the (provider ID, location ID) whose status changes then. The slot tick takes out the
+    // entries it has passed.
+    static BOOKING_BOUNDARIES: RefCell<BoundaryIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BOOKING_BOUNDARIES_MEMORY_ID)),
+        )
+    );
+
+    // Confirmed bookings per location, keyed by ("<provider_id>/<location_id>", start time)
+    static LOCATION_BOOKINGS: RefCell<StableBTreeMap<PairKey<String, u64>, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LOCATION_BOOKINGS_MEMORY_ID)),
+        )
+    );
+
+    // Maps booking IDs to the escrow holding their cost
+    static ESCROW_REGISTRY: RefCell<StableBTreeMap<String, BookingEscrow, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ESCROW_MEMORY_ID)),
+        )
+    );
+
+    // Maps device principals to the provider locations they play at
+    static DEVICE_REGISTRY: RefCell<StableBTreeMap<Principal, Device, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEVICE_MEMORY_ID)),
+        )
+    );
+
+    // Last attestation counter of removed devices, so a device registered again cannot replay
+    // plays it signed before
+    static RETIRED_DEVICE_COUNTERS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RETIRED_DEVICE_MEMORY_ID)),
+        )
+    );
+
+    // Append-only log of accepted plays. Log entries are never rewritten, so the post-upgrade
+    // migration does not visit them; older schema versions are upgraded when read.
+    static PLAY_LOG: RefCell<StableLog<PlayRecord, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PLAY_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(PLAY_LOG_DATA_MEMORY_ID)),
+        )
+    );
+
+    // Indexes and balances the rebuild passes after the migration still have to fill in
+    static PENDING_REBUILDS: RefCell<StableCell<PendingRebuilds, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_REBUILDS_MEMORY_ID)),
+            PendingRebuilds::default(),
+        )
+    );
+
+    // Position of the CPM billing engine in the play log
+    static BILLING_STATE: RefCell<StableCell<BillingState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BILLING_STATE_MEMORY_ID)),
+            BillingState::default(),
+        )
+    );
+
+    // Maps "<campaign_id>:<provider_id>/<location_id>" to impression billing per CPM location
+    static BILLING_ACCOUNTS: RefCell<StableBTreeMap<String, BillingAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BILLING_ACCOUNT_MEMORY_ID)),
+        )
+    );
+
+    // Every location keyed by (location ID, provider ID). Only the keys are kept; the location
+    // itself is read from the provider, so views and status are never stale here. Locations
+    // registered before IDs were generated by the canister may share an ID, so the provider is
+    // part of the key and each of them has its own entry.
+    static LOCATION_INDEX: RefCell<StableBTreeMap<PairKey<String, String>, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LOCATION_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // Locations with coordinates, keyed by (geohash, (provider ID, location ID)). Like the
+    // location index it only holds keys, and the provider is part of them because location IDs
+    // are not unique across providers.
+    static GEO_INDEX: RefCell<StableBTreeMap<GeoKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GEO_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // Inverted index for search: (term, document) to the term's weight in the document. Documents
+    // are "provider:<provider ID>" and "location:<provider ID>/<location ID>". Campaigns are not
+    // indexed: every campaign query is restricted to its owner, so campaigns have no public
+    // metadata that search could return.
+    static SEARCH_INDEX: RefCell<StableBTreeMap<PairKey<String, String>, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SEARCH_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // Records quarantined by the post-upgrade migration, keyed by "<registry>/<key>"
+    static DECODE_FAILURES: RefCell<StableBTreeMap<String, DecodeFailure, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DECODE_FAILURES_MEMORY_ID)),
+        )
+    );
+
+    // Migration progress only matters until the migration finishes, so it lives on the heap;
+    // an upgrade in the middle of a migration simply starts it over.
+    static MIGRATION_STATUS: RefCell<MigrationStatus> = RefCell::new(MigrationStatus::default());
+    static MIGRATION_CURSOR: RefCell<(usize, Option<Vec<u8>>)> = const { RefCell::new((0, None)) };
+
+    // Guard keys of the async payout operations currently awaiting the ledger
+    static OPERATIONS_IN_FLIGHT: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+const CAMPAIGN_SEQUENCE: &str = "campaign";
+const PROVIDER_SEQUENCE: &str = "provider";
+const DEPOSIT_SEQUENCE: &str = "deposit";
+const TRANSFER_SEQUENCE: &str = "transfer";
+const BOOKING_SEQUENCE: &str = "booking";
+const LOCATION_SEQUENCE: &str = "location";
+
+// Advances the named sequence and returns the new value
+fn next_sequence_value(sequence: &str) -> u64 {
+    ID_SEQUENCES.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut ids = cell.get().clone();
+        let value = ids.sequences.entry(sequence.to_string()).or_insert(0);
+        *value += 1;
+        let next = *value;
+        cell.set(ids);
+        next
+    })
+}
+
+// Makes sure the named sequence never hands out a value at or below `value` again
+fn bump_sequence_to(sequence: &str, value: u64) {
+    ID_SEQUENCES.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut ids = cell.get().clone();
+        let current = ids.sequences.entry(sequence.to_string()).or_insert(0);
+        if *current < value {
+            *current = value;
+            cell.set(ids);
+        }
+    })
+}
+
+// Highest numeric suffix among keys of the form "<prefix>_<n>"
+fn max_id_suffix(keys: impl Iterator<Item = String>, prefix: &str) -> u64 {
+    keys.filter_map(|key| key.strip_prefix(prefix)?.strip_prefix('_')?.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+// Generate unique campaign ID
+fn generate_campaign_id() -> String {
+    loop {
+        let id = format!("campaign_{}", next_sequence_value(CAMPAIGN_SEQUENCE));
+        // Never hand out an ID that is already taken, whatever state the sequence is in
+        if !CAMPAIGN_REGISTRY.with(|registry| registry.borrow().contains_key(&id)) {
+            return id;
+        }
+    }
+}
+
+// Generate unique provider ID
+fn generate_provider_id() -> String {
+    loop {
+        let id = format!("provider_{}", next_sequence_value(PROVIDER_SEQUENCE));
+        if !PROVIDER_REGISTRY.with(|registry| registry.borrow().contains_key(&id)) {
+            return id;
+        }
+    }
+}
+
+// Generate unique location ID. Locations registered before IDs were generated by the canister
+// can have any ID; the rebuild passes after an upgrade raise the sequence above the ones of this
+// form, and until then IDs found in the location index are skipped.
+fn generate_location_id() -> String {
+    loop {
+        let id = format!("location_{}", next_sequence_value(LOCATION_SEQUENCE));
+        let taken = LOCATION_INDEX.with(|index| {
+            index
+                .borrow()
+                .range(PairKey(id.clone(), String::new())..)
+                .next()
+                .is_some_and(|entry| entry.key().0 == id)
+        });
+        if !taken {
+            return id;
+        }
+    }
+}
+
+// All canister state lives in stable structures, so there is nothing to serialize here.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {}
+
+#[ic_cdk::init]
+fn init(config: Option<CanisterConfig>) {
+    set_config(config.unwrap_or_else(ledger_config));
+    start_transfer_retry_timer();
+    start_slot_timer();
+    start_billing_timer();
+}
+
+// Registries are only read in bounded batches after an upgrade: the migration and the rebuild
+// passes that follow it run on timers (see `run_migration_batch`).
+#[ic_cdk::post_upgrade]
+fn post_upgrade(config: Option<CanisterConfig>) {
+    set_config(config.unwrap_or_else(ledger_config));
+    plan_rebuilds();
+    start_migration();
+    start_transfer_retry_timer();
+    start_slot_timer();
+    start_billing_timer();
+}
+
+fn set_config(config: CanisterConfig) {
+    // The default token must always be usable, so it is (re)registered with the configuration
+    let token = TokenConfig::from(config.clone());
+    TOKEN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(token.symbol.clone(), token);
+    });
+    CONFIG.with(|cell| {
+        cell.borrow_mut().set(config);
+    });
+}
+
+#[ic_cdk::query]
+fn get_config() -> CanisterConfig {
+    ledger_config()
+}
+
+// Switching ledgers does not move existing balances, so this is meant for fixing a deployment's
+// configuration (e.g. pointing a local canister at the local ledger), not for migrating funds.
+#[ic_cdk::update]
+fn update_config(config: CanisterConfig) -> Result<(), SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can update the configuration".to_string()));
+    }
+
+    set_config(config);
+    Ok(())
+}
+
+// Looks up an allowed token by symbol
+fn token_config(symbol: &str) -> Result<TokenConfig, SoulboardError> {
+    TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .get(&symbol.to_string())
+            .ok_or_else(|| SoulboardError::not_found("token", symbol))
+    })
+}
+
+#[ic_cdk::query]
+fn get_tokens() -> Vec<TokenConfig> {
+    TOKEN_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+// Allows a new ICRC-1 token, or updates the ledger settings of an allowed one (controllers only)
+#[ic_cdk::update]
+fn add_token(token: TokenConfig) -> Result<(), SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can manage tokens".to_string()));
+    }
+    if token.symbol.is_empty() {
+        return Err(SoulboardError::InvalidInput("Token symbol must not be empty".to_string()));
+    }
+
+    // Keep the default token's configuration in step with the registry
+    let mut config = ledger_config();
+    if config.token_symbol == token.symbol {
+        config.ledger_canister_id = token.ledger_canister_id;
+        config.ledger_fee = token.fee.clone();
+        config.ledger_decimals = token.decimals;
+        set_config(config);
+    } else {
+        TOKEN_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(token.symbol.clone(), token);
+        });
+    }
+    Ok(())
+}
+
+// Disallows a token. Refused for the default token and while any campaign, location or provider
+// balance still uses it, since their funds could no longer be moved (controllers only).
+#[ic_cdk::update]
+fn remove_token(symbol: String) -> Result<(), SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can manage tokens".to_string()));
+    }
+    if ledger_config().token_symbol == symbol {
+        return Err(SoulboardError::Conflict("The default token cannot be removed".to_string()));
+    }
+
+    let used_by_campaign = CAMPAIGN_REGISTRY.with(|registry| {
+        registry.borrow().iter().any(|entry| entry.value().token == symbol)
+    });
+    let used_by_provider = PROVIDER_REGISTRY.with(|registry| {
+        registry.borrow().iter().any(|entry| {
+            let provider = entry.value();
+            provider.total_earnings.contains_key(&symbol)
+                || provider.locations.iter().any(|location| location.token == symbol)
+        })
+    });
+    if used_by_campaign || used_by_provider {
+        return Err(SoulboardError::Conflict(format!("Token {} is still in use", symbol)));
+    }
+
+    TOKEN_REGISTRY.with(|registry| {
+        registry.borrow_mut().remove(&symbol);
+    });
+    Ok(())
+}
+
+// Registries rewritten by the post-upgrade migration, in the order they are visited
+const MIGRATED_REGISTRIES: [&str; 12] = [
+    "campaigns",
+    "providers",
+    "earnings",
+    "deposits",
+    "tokens",
+    "pending_transfers",
+    "campaign_providers",
+    "bookings",
+    "escrows",
+    "devices",
+    "billing_accounts",
+    "receipts",
+];
+const MIGRATION_BATCH_SIZE: usize = 100;
+
+fn start_migration() {
+    MIGRATION_STATUS.with(|status| {
+        *status.borrow_mut() = MigrationStatus {
+            running: true,
+            current_registry: Some(MIGRATED_REGISTRIES[0].to_string()),
+            started_at: Some(time()),
+            ..Default::default()
+        };
+    });
+    MIGRATION_CURSOR.with(|cursor| *cursor.borrow_mut() = (0, None));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+}
+
+// Runs one batch of the migration and schedules the next one until it is done
+fn run_migration_batch() {
+    if migrate_next_batch() {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+    }
+}
+
+// Name of a migration step: the registries in MIGRATED_REGISTRIES, then the REBUILD_PASSES
+fn migration_step_name(index: usize) -> Option<String> {
+    MIGRATED_REGISTRIES.iter().chain(REBUILD_PASSES.iter()).nth(index).map(|name| name.to_string())
+}
+
+// Migrates one batch of records, one registry after the other, then runs the rebuild passes
+// batch by batch. Returns whether there is more to do.
+fn migrate_next_batch() -> bool {
+    let (index, cursor) = MIGRATION_CURSOR.with(|c| c.borrow().clone());
+    let next_cursor = match index {
+        0 => migrate_registry_batch(&CAMPAIGN_REGISTRY, CAMPAIGN_MEMORY_ID, MIGRATED_REGISTRIES[0], cursor),
+        1 => migrate_registry_batch(&PROVIDER_REGISTRY, PROVIDER_MEMORY_ID, MIGRATED_REGISTRIES[1], cursor),
+        2 => migrate_registry_batch(&EARNINGS_REGISTRY, EARNINGS_MEMORY_ID, MIGRATED_REGISTRIES[2], cursor),
+        3 => migrate_registry_batch(&DEPOSIT_REGISTRY, DEPOSIT_MEMORY_ID, MIGRATED_REGISTRIES[3], cursor),
+        4 => migrate_registry_batch(&TOKEN_REGISTRY, TOKEN_MEMORY_ID, MIGRATED_REGISTRIES[4], cursor),
+        5 => migrate_registry_batch(&PENDING_TRANSFERS, PENDING_TRANSFER_MEMORY_ID, MIGRATED_REGISTRIES[5], cursor),
+        6 => migrate_registry_batch(&CAMPAIGN_PROVIDER_LINKS, CAMPAIGN_PROVIDER_MEMORY_ID, MIGRATED_REGISTRIES[6], cursor),
+        7 => migrate_registry_batch(&BOOKING_REGISTRY, BOOKING_MEMORY_ID, MIGRATED_REGISTRIES[7], cursor),
+        8 => migrate_registry_batch(&ESCROW_REGISTRY, ESCROW_MEMORY_ID, MIGRATED_REGISTRIES[8], cursor),
+        9 => migrate_registry_batch(&DEVICE_REGISTRY, DEVICE_MEMORY_ID, MIGRATED_REGISTRIES[9], cursor),
+        10 => migrate_registry_batch(&BILLING_ACCOUNTS, BILLING_ACCOUNT_MEMORY_ID, MIGRATED_REGISTRIES[10], cursor),
+        11 => migrate_registry_batch(&RECEIPTS, RECEIPT_MEMORY_ID, MIGRATED_REGISTRIES[11], cursor),
+        12 => rebuild_campaigns_batch(cursor),
+        13 => rebuild_providers_batch(cursor),
+        14 => rebuild_escrows_batch(cursor),
+        15 => rebuild_receipts_batch(cursor),
+        16 => rebuild_bookings_batch(cursor),
+        _ => {
+            PENDING_REBUILDS.with(|cell| cell.borrow_mut().set(PendingRebuilds::default()));
+            MIGRATION_STATUS.with(|status| {
+                let mut status = status.borrow_mut();
+                status.running = false;
+                status.current_registry = None;
+                status.finished_at = Some(time());
+            });
+            return false;
+        }
+    };
+
+    let next = match next_cursor {
+        Some(key) => (index, Some(key)),
+        None => (index + 1, None),
+    };
+    MIGRATION_STATUS.with(|status| {
+        status.borrow_mut().current_registry = migration_step_name(next.0);
+    });
+    MIGRATION_CURSOR.with(|c| *c.borrow_mut() = next);
+    true
+}
+
+// Passes over the registries that follow the migration. They raise the ID sequences above the
+// stored IDs, and fill the indexes and opening balances PendingRebuilds lists, in bounded batches
+// so that no upgrade or timer run reads a whole registry.
+const REBUILD_PASSES: [&str; 5] = [
+    "campaigns (rebuild)",
+    "providers (rebuild)",
+    "escrows (rebuild)",
+    "receipts (rebuild)",
+    "bookings (rebuild)",
+];
+
+// Records up to MIGRATION_BATCH_SIZE entries of a registry that follow `cursor`
+fn registry_batch<K: Storable + Ord + Clone, V: Storable>(
+    registry: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    cursor: Option<Vec<u8>>,
+) -> Vec<(K, V)> {
+    let after = cursor.map(|key| K::from_bytes(Cow::Owned(key)));
+    registry.with(|registry| {
+        registry
+            .borrow()
+            .range(after_cursor(after))
+            .take(MIGRATION_BATCH_SIZE)
+            .map(|entry| (entry.key().clone(), entry.value()))
+            .collect()
+    })
+}
+
+// Cursor after a batch of `registry_batch`, or None once the registry has been visited
+fn batch_cursor<K: Storable, V>(batch: &[(K, V)]) -> Option<Vec<u8>> {
+    if batch.len() < MIGRATION_BATCH_SIZE {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    }
+}
+
+fn pending_rebuilds() -> PendingRebuilds {
+    PENDING_REBUILDS.with(|cell| cell.borrow().get().clone())
+}
+
+// Decides what the rebuild passes of this upgrade fill in. Indexes and the transaction log are
+// kept up to date by every change once they exist, so only empty ones are filled; what an
+// interrupted earlier pass left pending stays pending.
+fn plan_rebuilds() {
+    let mut pending = pending_rebuilds();
+    pending.location_index |= LOCATION_INDEX.with(|index| index.borrow().is_empty());
+    pending.search_index |= SEARCH_INDEX.with(|index| index.borrow().is_empty());
+    pending.receipt_index |= RECEIPT_OWNER_INDEX.with(|index| index.borrow().is_empty());
+    pending.geo_index |= GEO_INDEX.with(|index| index.borrow().is_empty());
+    pending.owner_index |= CAMPAIGN_OWNER_INDEX.with(|index| index.borrow().is_empty())
+        || PROVIDER_OWNER_INDEX.with(|index| index.borrow().is_empty());
+    pending.campaign_bookings |= CAMPAIGN_BOOKINGS.with(|index| index.borrow().is_empty());
+    pending.lifecycle_index |= CAMPAIGN_LIFECYCLE_INDEX.with(|index| index.borrow().is_empty());
+    pending.booking_boundaries |= BOOKING_BOUNDARIES.with(|index| index.borrow().is_empty());
+    if TRANSACTION_LOG.with(|log| log.borrow().len()) == 0 {
+        pending.opening_balances = true;
+        // The journal only holds transfers in flight, so it is opened right away
+        open_pending_transfers();
+    }
+    PENDING_REBUILDS.with(|cell| cell.borrow_mut().set(pending));
+}
+
+fn rebuild_campaigns_batch(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    let batch = registry_batch(&CAMPAIGN_REGISTRY, cursor);
+    bump_sequence_to(CAMPAIGN_SEQUENCE, max_id_suffix(batch.iter().map(|(key, _)| key.clone()), CAMPAIGN_SEQUENCE));
+    let pending = pending_rebuilds();
+    if pending.owner_index {
+        for (_, campaign) in &batch {
+            index_owner(&CAMPAIGN_OWNER_INDEX, CAMPAIGN_SEQUENCE, campaign.owner, &campaign.id);
+        }
+    }
+    if pending.lifecycle_index {
+        CAMPAIGN_LIFECYCLE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (campaign_id, campaign) in &batch {
+                if let Some(due) = next_transition_time(campaign) {
+                    index.insert(PairKey(due, campaign_id.clone()), ());
+                }
+            }
+        });
+    }
+    if pending.opening_balances {
+        for (_, campaign) in &batch {
+            let account = TransactionAccount::Campaign(campaign.id.clone());
+            open_balance(account, campaign.budget.clone(), &campaign.token, Some(campaign.id.clone()));
+        }
+    }
+    batch_cursor(&batch)
+}
+
+fn rebuild_providers_batch(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    let batch = registry_batch(&PROVIDER_REGISTRY, cursor);
+    let pending = pending_rebuilds();
+    bump_sequence_to(PROVIDER_SEQUENCE, max_id_suffix(batch.iter().map(|(key, _)| key.clone()), PROVIDER_SEQUENCE));
+    let location_ids = batch.iter().flat_map(|(_, provider)| &provider.locations).map(|location| location.id.clone());
+    bump_sequence_to(LOCATION_SEQUENCE, max_id_suffix(location_ids, LOCATION_SEQUENCE));
+
+    for (_, provider) in &batch {
+        if pending.location_index {
+            LOCATION_INDEX.with(|index| {
+                let mut index = index.borrow_mut();
+                for location in &provider.locations {
+                    index.insert(PairKey(location.id.clone(), provider.id.clone()), ());
+                }
+            });
+        }
+        if pending.owner_index {
+            index_owner(&PROVIDER_OWNER_INDEX, PROVIDER_SEQUENCE, provider.owner, &provider.id);
+        }
+        if pending.geo_index {
+            GEO_INDEX.with(|index| {
+                let mut index = index.borrow_mut();
+                for key in geo_index_keys(provider) {
+                    index.insert(key, ());
+                }
+            });
+        }
+        // Indexing a provider's current documents again changes nothing, so providers saved
+        // since the upgrade are safe to visit
+        if pending.search_index {
+            reindex_search_documents(None, Some(provider));
+        }
+        if pending.opening_balances {
+            for (token, amount) in &provider.total_earnings {
+                open_balance(TransactionAccount::Provider(provider.id.clone()), amount.clone(), token, None);
+            }
+        }
+    }
+    batch_cursor(&batch)
+}
+
+fn rebuild_escrows_batch(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    if !pending_rebuilds().opening_balances {
+        return None;
+    }
+    let batch = registry_batch(&ESCROW_REGISTRY, cursor);
+    for (_, escrow) in &batch {
+        let account = TransactionAccount::Escrow(escrow.booking_id.clone());
+        open_balance(account, escrow.escrowed.clone(), &escrow.token, Some(escrow.campaign_id.clone()));
+    }
+    batch_cursor(&batch)
+}
+
+fn rebuild_receipts_batch(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    if !pending_rebuilds().receipt_index {
+        return None;
+    }
+    let batch = registry_batch(&RECEIPTS, cursor);
+    RECEIPT_OWNER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (transaction_id, receipt) in &batch {
+            index.insert(PairKey(receipt.owner, *transaction_id), ());
+        }
+    });
+    batch_cursor(&batch)
+}
+
+fn rebuild_bookings_batch(cursor: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    let pending = pending_rebuilds();
+    if !pending.campaign_bookings && !pending.booking_boundaries {
+        return None;
+    }
+    let batch = registry_batch(&BOOKING_REGISTRY, cursor);
+    let now = time();
+    for (booking_id, booking) in &batch {
+        if pending.campaign_bookings {
+            CAMPAIGN_BOOKINGS.with(|index| {
+                index.borrow_mut().insert(PairKey(booking.campaign_id.clone(), booking_id.clone()), ());
+            });
+        }
+        if pending.booking_boundaries && booking.status == BookingStatus::Confirmed && booking.end_time > now {
+            add_booking_boundaries(booking);
+        }
+    }
+    batch_cursor(&batch)
+}
+
+// Rewrites up to MIGRATION_BATCH_SIZE records that follow `cursor` in the current schema version
+// and quarantines the ones that cannot be decoded. The registry is read through a raw view of its
+// memory, so a bad record never reaches `Storable::from_bytes`. Returns the raw key of the last
+// record visited, or None once the whole registry has been visited.
+fn migrate_registry_batch<K, V>(
+    registry: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    memory_id: MemoryId,
+    name: &str,
+    cursor: Option<Vec<u8>>,
+) -> Option<Vec<u8>>
+where
+    K: Storable + Ord + Clone + Display,
+    V: VersionedValue + Storable,
+{
+    let mut raw: StableBTreeMap<K, Vec<u8>, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(memory_id)));
+
+    let lower = match cursor {
+        Some(key) => RangeBound::Excluded(K::from_bytes(Cow::Owned(key))),
+        None => RangeBound::Unbounded,
+    };
+    let batch: Vec<(K, Vec<u8>)> = raw
+        .range((lower, RangeBound::Unbounded))
+        .take(MIGRATION_BATCH_SIZE)
+        .map(|entry| entry.into_pair())
+        .collect();
+
+    let (mut migrated, mut quarantined) = (0, 0);
+    for (key, bytes) in &batch {
+        let version = stored_version(bytes);
+        match decode_versioned::<V>(bytes) {
+            Ok(value) => {
+                if version != V::SCHEMA_VERSION {
+                    raw.insert(key.clone(), encode_versioned(&value));
+                    migrated += 1;
+                }
+            }
+            Err(error) => {
+                raw.remove(key);
+                let failure = DecodeFailure {
+                    registry: name.to_string(),
+                    key: key.to_string(),
+                    stored_version: version,
+                    error,
+                    detected_at: time(),
+                    raw: bytes.clone(),
+                };
+                DECODE_FAILURES.with(|failures| {
+                    failures.borrow_mut().insert(format!("{}/{}", name, key), failure);
+                });
+                quarantined += 1;
+            }
+        }
+    }
+    drop(raw);
+
+    // The raw view may have moved the root node, so the typed map has to be reloaded
+    registry.with(|r| {
+        *r.borrow_mut() = StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(memory_id)));
+    });
+    MIGRATION_STATUS.with(|status| {
+        let mut status = status.borrow_mut();
+        status.migrated += migrated;
+        status.quarantined += quarantined;
+    });
+
+    if batch.len() < MIGRATION_BATCH_SIZE {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    }
+}
+
+#[ic_cdk::query]
+fn get_migration_status() -> MigrationStatus {
+    MIGRATION_STATUS.with(|status| status.borrow().clone())
+}
+
+// Records quarantined by migrations. Raw records can hold private campaign data, so this is
+// restricted to controllers.
+#[ic_cdk::query]
+fn get_decode_failures() -> Result<Vec<DecodeFailure>, SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can view decode failures".to_string()));
+    }
+
+    DECODE_FAILURES.with(|failures| {
+        Ok(failures
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .collect())
+    })
+}
+
+// Registers a new provider for the calling wallet
+#[ic_cdk::update]
+fn register_provider(name: String, locations: Vec<LocationInput>) -> Result<String, SoulboardError> {
+    let caller_principal = caller();
+
+    let locations = locations
+        .into_iter()
+        .map(new_location)
+        .collect::<Result<Vec<Location>, SoulboardError>>()?;
+
+    let provider_id = generate_provider_id();
+    
+    let provider = Provider {
+        id: provider_id.clone(),
+        name,
+        owner: caller_principal,
+        locations,
+        total_earnings: BTreeMap::new(),
+    };
+
+    save_provider(provider);
+
+    Ok(provider_id)
+}
+
+// Creates a new campaign (private to the caller)
+#[ic_cdk::update]
+fn create_campaign(
+    name: String,
+    description: String,
+    image: Option<String>,
+    locations: Option<Vec<Location>>,
+    token: Option<String>,
+) -> Result<String, SoulboardError> {
+    let caller_principal = caller();
+
+    // The budget is held in a single token, chosen at creation (the default token if omitted)
+    let token = token_config(&token.unwrap_or_else(|| ledger_config().token_symbol))?;
+
+    let campaign_id = generate_campaign_id();
+    
+    let campaign = Campaign {
+        id: campaign_id.clone(),
+        name,
+        description,
+        image,
+        locations,
+        // Budget only comes from ledger deposits made through fund_campaign
+        budget: NumTokens::from(0u64),
+        token: token.symbol,
+        owner: caller_principal,
+        status: CampaignStatus::Draft,
+        start_time: None,
+        end_time: None,
+        ended_at: None,
+        refund_block_index: None,
+    };
+
+    CAMPAIGN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(campaign_id.clone(), campaign);
+    });
+    index_owner(&CAMPAIGN_OWNER_INDEX, CAMPAIGN_SEQUENCE, caller_principal, &campaign_id);
+
+    Ok(campaign_id)
+}
+
+// Adds a campaign or provider to its owner's index and counts it. Indexing an entry again
+// changes nothing, so the rebuild pass can visit records created since the upgrade.
+fn index_owner(index: &'static LocalKey<RefCell<OwnerIndex>>, kind: &str, owner: Principal, id: &str) {
+    let added = index.with(|index| index.borrow_mut().insert(PairKey(owner, id.to_string()), ()).is_none());
+    if added {
+        OWNER_COUNTS.with(|counts| {
+            let mut counts = counts.borrow_mut();
+            let key = PairKey(owner, kind.to_string());
+            let count = counts.get(&key).unwrap_or(0);
+            counts.insert(key, count + 1);
+        });
+    }
+}
+
+fn owner_count(owner: Principal, kind: &str) -> u64 {
+    OWNER_COUNTS.with(|counts| counts.borrow().get(&PairKey(owner, kind.to_string()))).unwrap_or(0)
+}
+
+// IDs in an owner's index after a page cursor, in ID order
+fn owned_ids(index: &OwnerIndex, owner: Principal, cursor: Option<String>) -> impl Iterator<Item = String> + '_ {
+    let start = match cursor {
+        Some(cursor) => RangeBound::Excluded(PairKey(owner, cursor)),
+        None => RangeBound::Included(PairKey(owner, String::new())),
+    };
+    index
+        .range((start, RangeBound::Unbounded))
+        .take_while(move |entry| entry.key().0 == owner)
+        .map(|entry| entry.key().1.clone())
+}
+
+// Marks the principals, providers and campaigns an async payout operation works on for as long as
+// the operation runs. Balances are debited before the ledger call and restored afterwards on a
+// definite failure, so a second operation on the same keys while the first one is awaiting the
+// ledger would see an intermediate state; it is rejected instead.
+struct OperationGuard {
+    keys: Vec<String>,
+}
+
+impl OperationGuard {
+    fn acquire(keys: Vec<String>) -> Result<Self, SoulboardError> {
+        OPERATIONS_IN_FLIGHT.with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            if let Some(busy) = keys.iter().find(|key| in_flight.contains(*key)) {
+                return Err(SoulboardError::Conflict(format!("another operation on {} is in progress, try again later", busy)));
+            }
+            in_flight.extend(keys.iter().cloned());
+            Ok(Self { keys })
+        })
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        OPERATIONS_IN_FLIGHT.with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            for key in &self.keys {
+                in_flight.remove(key);
+            }
+        });
+    }
+}
+
+fn principal_guard_key(principal: &Principal) -> String {
+    format!("principal:{}", principal)
+}
+
+fn provider_guard_key(provider_id: &str) -> String {
+    format!("provider:{}", provider_id)
+}
+
+fn campaign_guard_key(campaign_id: &str) -> String {
+    format!("campaign:{}", campaign_id)
+}
+
+// Ledger configuration, set through the init/post_upgrade argument or update_config
+fn ledger_config() -> CanisterConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Why a ledger transfer failed. A definite failure means the ledger did not move any funds, so
+// balances debited for the transfer can be restored. After an uncertain outcome the transfer may
+// still have been executed, and nothing may be rolled back.
+enum TransferFailure {
+    Definite(SoulboardError),
+    Uncertain(SoulboardError),
+}
+
+impl Display for TransferFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferFailure::Definite(msg) => write!(f, "{}", msg),
+            TransferFailure::Uncertain(msg) => write!(f, "outcome unknown: {}", msg),
+        }
+    }
+}
+
+// Classifies a rejected ledger call. SYS_TRANSIENT rejects and codes this CDK does not know (such
+// as SYS_UNKNOWN) do not tell whether the ledger executed the message; every other reject means
+// it did not.
+fn call_failure(code: RejectionCode, msg: String) -> TransferFailure {
+    let error = SoulboardError::CallFailed { code, msg };
+    match code {
+        RejectionCode::SysTransient | RejectionCode::Unknown => TransferFailure::Uncertain(error),
+        _ => TransferFailure::Definite(error),
+    }
+}
+
+/// Transfers some tokens to the specified account on the token's ledger.
+#[cfg(not(test))]
+async fn ledger_transfer(
+    token: &TokenConfig,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    memo: Option<Vec<u8>>,
+    amount: NumTokens,
+    created_at_time: u64,
+) -> Result<BlockIndex, TransferFailure> {
+    let args = TransferArg {
+        // A "memo" is an arbitrary blob that has no meaning to the ledger, but can be used by
+        // the sender or receiver to attach additional information to the transaction.
+        memo: memo.map(Memo::from),
+        to,
+        amount,
+        // The ledger supports subaccounts. You can pick the subaccount of the caller canister's
+        // account to use for transferring the tokens. If you don't specify a subaccount, the default
+        // subaccount of the caller's account is used.
+        from_subaccount,
+        // The ledger charges a fee for transfers, which is deducted from the sender's account.
+        // Passing the configured fee makes the ledger reject the transfer if the fee changed.
+        fee: Some(token.fee.clone()),
+        // The created_at_time is used for deduplication: a retry with the same arguments within
+        // the ledger's deduplication window is answered with `Duplicate` instead of paying twice.
+        created_at_time: Some(created_at_time),
+    };
+
+    // Make the inter-canister call to the ledger
+    match call(token.ledger_canister_id, "icrc1_transfer", (args,)).await {
+        Ok((result,)) => {
+            let transfer_result: Result<BlockIndex, TransferError> = result;
+            match transfer_result {
+                Ok(block_index) => Ok(block_index),
+                // An earlier attempt of this very transfer went through
+                Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+                Err(e) => Err(TransferFailure::Definite(SoulboardError::LedgerError(e))),
+            }
+        }
+        Err((code, msg)) => Err(call_failure(code, msg)),
+    }
+}
+
+/// Moves tokens out of `from` using an ICRC-2 allowance that `from` granted to this canister.
+#[cfg(not(test))]
+async fn ledger_transfer_from(
+    token: &TokenConfig,
+    from: Account,
+    to: Account,
+    memo: Option<Vec<u8>>,
+    amount: NumTokens,
+    created_at_time: u64,
+) -> Result<BlockIndex, TransferFailure> {
+    let args = TransferFromArgs {
+        // The allowance was granted to this canister's default account.
+        spender_subaccount: None,
+        from,
+        to,
+        // The ledger moves exactly `amount` to `to` and charges the fee to `from` on top of it,
+        // so the approved allowance has to cover `amount` plus the fee.
+        amount,
+        fee: Some(token.fee.clone()),
+        memo: memo.map(Memo::from),
+        created_at_time: Some(created_at_time),
+    };
+
+    match call(token.ledger_canister_id, "icrc2_transfer_from", (args,)).await {
+        Ok((result,)) => {
+            let transfer_result: Result<BlockIndex, TransferFromError> = result;
+            match transfer_result {
+                Ok(block_index) => Ok(block_index),
+                Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+                // An InsufficientAllowance error means the approval has to cover the amount plus the fee
+                Err(e) => Err(TransferFailure::Definite(SoulboardError::LedgerTransferFromError(e))),
+            }
+        }
+        Err((code, msg)) => Err(call_failure(code, msg)),
+    }
+}
+
+/// Returns the balance of the specified account on the token's ledger.
+async fn ledger_balance_of(token: &TokenConfig, account: Account) -> Result<NumTokens, SoulboardError> {
+    match call(token.ledger_canister_id, "icrc1_balance_of", (account,)).await {
+        Ok((balance,)) => Ok(balance),
+        Err((code, msg)) => Err(SoulboardError::CallFailed { code, msg }),
+    }
+}
+
+// Helper function to create an account from a principal
+fn principal_to_account(principal: Principal) -> Account {
+    Account {
+        owner: principal,
+        subaccount: None,
+    }
+}
+
+// Records a transfer in the journal before it is sent. The memo and created_at_time are fixed
+// here, so every later attempt sends the exact same transaction and the ledger deduplicates it.
+// A payout's caller has already debited its balance by the amount plus the token's fee.
+fn journal_transfer(
+    purpose: TransferPurpose,
+    token: &TokenConfig,
+    source: TransferSource,
+    to: Account,
+    amount: NumTokens,
+) -> u64 {
+    let id = next_sequence_value(TRANSFER_SEQUENCE);
+    let now = time();
+    let debited = transfer_balance_account(&purpose).map(|_| amount.clone() + token.fee.clone());
+    let transfer = PendingTransfer {
+        id,
+        purpose,
+        token: token.symbol.clone(),
+        source,
+        to,
+        amount,
+        fee: token.fee.clone(),
+        memo: format!("soulboard:{}", id).into_bytes(),
+        created_at_time: now,
+        status: PendingTransferStatus::Pending,
+        debited,
+        attempts: 0,
+        last_error: None,
+        updated_at: now,
+    };
+    log_transfer_hold(&transfer);
+    PENDING_TRANSFERS.with(|journal| {
+        journal.borrow_mut().insert(id, transfer);
+    });
+    id
+}
+
+fn transfer_guard_key(transfer_id: u64) -> String {
+    format!("transfer:{}", transfer_id)
+}
+
+// Sends a journaled transfer and settles its outcome. Only one attempt per transfer runs at a
+// time; the guard also keeps the retry timer away from a transfer that is still awaiting the
+// ledger.
+async fn execute_journaled_transfer(transfer_id: u64) -> Result<BlockIndex, TransferFailure> {
+    let _guard = OperationGuard::acquire(vec![transfer_guard_key(transfer_id)])
+        .map_err(TransferFailure::Uncertain)?;
+
+    let transfer = PENDING_TRANSFERS.with(|journal| journal.borrow().get(&transfer_id))
+        .ok_or_else(|| TransferFailure::Uncertain(SoulboardError::not_found("transfer", transfer_id)))?;
+    let token = match token_config(&transfer.token) {
+        Ok(token) => token,
+        // Leave the transfer journaled until its token is configured again
+        Err(e) => return Err(TransferFailure::Uncertain(e)),
+    };
+
+    let memo = Some(transfer.memo.clone());
+    let amount = transfer.amount.clone();
+    let outcome = match &transfer.source {
+        TransferSource::Canister(from_subaccount) => {
+            ledger_transfer(&token, *from_subaccount, transfer.to, memo, amount, transfer.created_at_time).await
+        }
+        TransferSource::Allowance(from) => {
+            ledger_transfer_from(&token, *from, transfer.to, memo, amount, transfer.created_at_time).await
+        }
+    };
+
+    settle_transfer(transfer_id, &outcome);
+    outcome
+}
+
+// Applies the outcome of a transfer attempt. A transfer is settled at most once: completed and
+// definitely failed transfers leave the journal, so a late duplicate outcome finds nothing to do.
+fn settle_transfer(transfer_id: u64, outcome: &Result<BlockIndex, TransferFailure>) {
+    let Some(mut transfer) = PENDING_TRANSFERS.with(|journal| journal.borrow().get(&transfer_id)) else {
+        return;
+    };
+
+    match outcome {
+        Ok(block_index) => {
+            PENDING_TRANSFERS.with(|journal| journal.borrow_mut().remove(&transfer_id));
+            complete_transfer(&transfer, block_index);
+        }
+        Err(TransferFailure::Definite(_)) => {
+            PENDING_TRANSFERS.with(|journal| journal.borrow_mut().remove(&transfer_id));
+            roll_back_transfer(&transfer);
+        }
+        Err(TransferFailure::Uncertain(reason)) => {
+            transfer.status = PendingTransferStatus::Uncertain;
+            transfer.attempts += 1;
+            transfer.last_error = Some(reason.to_string());
+            transfer.updated_at = time();
+            PENDING_TRANSFERS.with(|journal| journal.borrow_mut().insert(transfer_id, transfer));
+        }
+    }
+}
+
+// Credits incoming funds, or records the refund of a closed campaign, once their transfer has gone
+// through
+fn complete_transfer(transfer: &PendingTransfer, block_index: &BlockIndex) {
+    match &transfer.purpose {
+        TransferPurpose::CampaignFunding { campaign_id } | TransferPurpose::DepositSweep { campaign_id } => {
+            credit_deposit(transfer, campaign_id, block_index);
+        }
+        // The refund of a closed campaign is kept with the campaign
+        TransferPurpose::CampaignCloseRefund { campaign_id } => {
+            CAMPAIGN_REGISTRY.with(|registry| {
+                let mut registry_borrow = registry.borrow_mut();
+                if let Some(mut campaign) = registry_borrow.get(campaign_id) {
+                    campaign.refund_block_index = Some(block_index.clone());
+                    registry_borrow.insert(campaign_id.clone(), campaign);
+                }
+            });
+        }
+        // Outgoing payouts were debited before the transfer was sent
+        TransferPurpose::ProviderWithdrawal { .. } | TransferPurpose::CampaignWithdrawal { .. } => {}
+    }
+    log_transfer_completion(transfer, block_index);
+    record_transfer_receipt(transfer, block_index);
+}
+
+// Credits an incoming transfer to the campaign budget and records it as a deposit
+fn credit_deposit(transfer: &PendingTransfer, campaign_id: &String, block_index: &BlockIndex) {
+    CAMPAIGN_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        if let Some(mut campaign) = registry_borrow.get(campaign_id) {
+            campaign.budget += transfer.amount.clone();
+            registry_borrow.insert(campaign_id.clone(), campaign);
+        }
+    });
+
+    let from = match &transfer.source {
+        TransferSource::Allowance(from) => *from,
+        TransferSource::Canister(subaccount) => Account {
+            owner: ic_cdk::api::id(),
+            subaccount: *subaccount,
+        },
+    };
+    let deposit_id = format!("deposit_{}", next_sequence_value(DEPOSIT_SEQUENCE));
+    let deposit = Deposit {
+        id: deposit_id.clone(),
+        campaign_id: campaign_id.clone(),
+        from,
+        amount: transfer.amount.clone(),
+        token: transfer.token.clone(),
+        fee: transfer.fee.clone(),
+        block_index: block_index.clone(),
+        timestamp: time(),
+    };
+    DEPOSIT_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(deposit_id, deposit);
+    });
+}
+
+fn record_transfer_receipt(transfer: &PendingTransfer, block_index: &BlockIndex) {
+    let (kind, campaign_id, provider_id) = match &transfer.purpose {
+        TransferPurpose::CampaignFunding { campaign_id } => (ReceiptKind::CampaignFunding, Some(campaign_id), None),
+        TransferPurpose::DepositSweep { campaign_id } => (ReceiptKind::DepositSweep, Some(campaign_id), None),
+        TransferPurpose::CampaignWithdrawal { campaign_id } => (ReceiptKind::CampaignWithdrawal, Some(campaign_id), None),
+        TransferPurpose::CampaignCloseRefund { campaign_id } => (ReceiptKind::CampaignCloseRefund, Some(campaign_id), None),
+        TransferPurpose::ProviderWithdrawal { provider_id } => (ReceiptKind::ProviderWithdrawal, None, Some(provider_id)),
+    };
+    // The campaign or provider may be gone by the time a retried transfer settles
+    let (owner, balance) = match (campaign_id, provider_id) {
+        (Some(campaign_id), _) => CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(campaign_id))
+            .map(|campaign| (campaign.owner, campaign.budget)),
+        (_, Some(provider_id)) => PROVIDER_REGISTRY.with(|registry| registry.borrow().get(provider_id))
+            .map(|provider| {
+                let balance = provider.total_earnings.get(&transfer.token).cloned().unwrap_or_default();
+                (provider.owner, balance)
+            }),
+        (None, None) => None,
+    }
+    .unwrap_or((transfer.to.owner, NumTokens::from(0u64)));
+
+    record_receipt(Receipt {
+        transaction_id: transfer.id,
+        kind,
+        owner,
+        campaign_id: campaign_id.cloned(),
+        provider_id: provider_id.cloned(),
+        token: transfer.token.clone(),
+        amount: transfer.amount.clone(),
+        fee: transfer.fee.clone(),
+        balance,
+        block_index: Some(block_index.clone()),
+        timestamp: time(),
+    });
+}
+
+fn record_receipt(receipt: Receipt) {
+    RECEIPT_OWNER_INDEX.with(|index| {
+        index.borrow_mut().insert(PairKey(receipt.owner, receipt.transaction_id), ());
+    });
+    RECEIPTS.with(|receipts| {
+        receipts.borrow_mut().insert(receipt.transaction_id, receipt);
+    });
+}
+
+fn stored_receipt(transaction_id: u64) -> Result<Receipt, SoulboardError> {
+    RECEIPTS.with(|receipts| receipts.borrow().get(&transaction_id))
+        .ok_or_else(|| SoulboardError::not_found("receipt", transaction_id))
+}
+
+// A receipt can be read by the owner it was issued to, the owner of the provider it paid, and
+// controllers
+#[ic_cdk::query]
+fn get_receipt(transaction_id: u64) -> Result<Receipt, SoulboardError> {
+    let caller_principal = caller();
+    let receipt = stored_receipt(transaction_id)?;
+    let pays_caller = receipt.provider_id.as_ref().is_some_and(|provider_id| {
+        PROVIDER_REGISTRY.with(|registry| registry.borrow().get(provider_id))
+            .is_some_and(|provider| provider.owner == caller_principal)
+    });
+    if receipt.owner != caller_principal && !pays_caller && !is_controller(&caller_principal) {
+        return Err(SoulboardError::Unauthorized("You can only view your own receipts".to_string()));
+    }
+    Ok(receipt)
+}
+
+// Receipts issued to the caller, oldest first
+#[ic_cdk::query]
+fn get_my_receipts(page: PageRequest) -> Result<Page<Receipt>, SoulboardError> {
+    let caller_principal = caller();
+    let start = match page_cursor::<u64>(&page)? {
+        Some(cursor) => RangeBound::Excluded(PairKey(caller_principal, cursor)),
+        None => RangeBound::Included(PairKey(caller_principal, 0)),
+    };
+
+    RECEIPT_OWNER_INDEX.with(|index| {
+        let index = index.borrow();
+        let matches = index
+            .range((start, RangeBound::Unbounded))
+            .take_while(|entry| entry.key().0 == caller_principal)
+            .filter_map(|entry| {
+                let transaction_id = entry.key().1;
+                RECEIPTS.with(|receipts| receipts.borrow().get(&transaction_id)).map(|receipt| (transaction_id, receipt))
+            });
+        Ok(paginate(matches, page.limit))
+    })
+}
+
+fn new_transaction(
+    kind: TransactionKind,
+    from: TransactionAccount,
+    to: TransactionAccount,
+    amount: NumTokens,
+    token: &str,
+) -> TransactionRecord {
+    TransactionRecord {
+        id: 0,
+        kind,
+        from,
+        to,
+        amount,
+        token: token.to_string(),
+        memo: String::new(),
+        caller: caller(),
+        timestamp: time(),
+        block_index: None,
+        campaign_id: None,
+    }
+}
+
+fn account_index_key(account: &TransactionAccount) -> String {
+    format!("account/{}", account.key())
+}
+
+fn campaign_index_key(campaign_id: &str) -> String {
+    format!("campaign/{}", campaign_id)
+}
+
+// Appends a movement to the transaction log and indexes it by both accounts and its campaign.
+// Zero amounts are not recorded. A movement that cannot be logged traps, so the balance change
+// it describes is rolled back with it.
+fn record_transaction(mut record: TransactionRecord) {
+    if record.amount == 0u64 {
+        return;
+    }
+    record.id = TRANSACTION_LOG.with(|log| log.borrow().len());
+    let mut keys = vec![account_index_key(&record.from), account_index_key(&record.to)];
+    keys.extend(record.campaign_id.as_deref().map(campaign_index_key));
+
+    let id = record.id;
+    if let Err(e) = TRANSACTION_LOG.with(|log| log.borrow().append(&record)) {
+        ic_cdk::trap(&format!("Failed to log transaction: {:?}", e));
+    }
+    TRANSACTION_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in keys {
+            index.insert(PairKey(key, id), ());
+        }
+    });
+}
+
+// Campaign budget or provider earnings an outgoing transfer is paid from
+fn transfer_balance_account(purpose: &TransferPurpose) -> Option<(TransactionAccount, Option<String>)> {
+    match purpose {
+        TransferPurpose::ProviderWithdrawal { provider_id } => Some((TransactionAccount::Provider(provider_id.clone()), None)),
+        TransferPurpose::CampaignWithdrawal { campaign_id } | TransferPurpose::CampaignCloseRefund { campaign_id } => {
+            Some((TransactionAccount::Campaign(campaign_id.clone()), Some(campaign_id.clone())))
+        }
+        TransferPurpose::CampaignFunding { .. } | TransferPurpose::DepositSweep { .. } => None,
+    }
+}
+
+// What a payout took from its balance, and what a definite failure gives back
+fn transfer_debit(transfer: &PendingTransfer) -> NumTokens {
+    transfer.debited.clone().unwrap_or_else(|| transfer.amount.clone())
+}
+
+fn transfer_memo(transfer: &PendingTransfer) -> String {
+    String::from_utf8_lossy(&transfer.memo).into_owned()
+}
+
+// Logs the funds an outgoing transfer takes from its balance when it is journaled
+fn log_transfer_hold(transfer: &PendingTransfer) {
+    if let Some((account, campaign_id)) = transfer_balance_account(&transfer.purpose) {
+        record_transaction(TransactionRecord {
+            memo: transfer_memo(transfer),
+            campaign_id,
+            ..new_transaction(
+                TransactionKind::Withdrawal,
+                account,
+                TransactionAccount::Transfer(transfer.id),
+                transfer_debit(transfer),
+                &transfer.token,
+            )
+        });
+    }
+}
+
+// Logs a settled transfer: deposits reach the campaign budget, held payouts leave for the
+// recipient, and the ledger fee is paid from the held funds of a payout, or else from whichever
+// canister account sent the transfer
+fn log_transfer_completion(transfer: &PendingTransfer, block_index: &BlockIndex) {
+    let sender = match &transfer.source {
+        TransferSource::Allowance(from) => TransactionAccount::External(*from),
+        TransferSource::Canister(_) if transfer.debited.is_some() => TransactionAccount::Transfer(transfer.id),
+        TransferSource::Canister(None) => TransactionAccount::Canister,
+        TransferSource::Canister(Some(subaccount)) => TransactionAccount::External(Account {
+            owner: ic_cdk::api::id(),
+            subaccount: Some(*subaccount),
+        }),
+    };
+    let (kind, from, to, campaign_id) = match (&transfer.purpose, transfer_balance_account(&transfer.purpose)) {
+        (TransferPurpose::CampaignFunding { campaign_id } | TransferPurpose::DepositSweep { campaign_id }, _) => (
+            TransactionKind::Deposit,
+            sender.clone(),
+            TransactionAccount::Campaign(campaign_id.clone()),
+            Some(campaign_id.clone()),
+        ),
+        (_, Some((_, campaign_id))) => (
+            TransactionKind::Withdrawal,
+            TransactionAccount::Transfer(transfer.id),
+            TransactionAccount::External(transfer.to),
+            campaign_id,
+        ),
+        (_, None) => return,
+    };
+    record_transaction(TransactionRecord {
+        memo: transfer_memo(transfer),
+        block_index: Some(block_index.clone()),
+        campaign_id: campaign_id.clone(),
+        ..new_transaction(kind, from, to, transfer.amount.clone(), &transfer.token)
+    });
+
+    // The fee of an allowance transfer is charged to the payer, outside the canister
+    if !matches!(transfer.source, TransferSource::Allowance(_)) {
+        record_transaction(TransactionRecord {
+            memo: transfer_memo(transfer),
+            block_index: Some(block_index.clone()),
+            campaign_id,
+            ..new_transaction(TransactionKind::Fee, sender, TransactionAccount::LedgerFees, transfer.fee.clone(), &transfer.token)
+        });
+    }
+}
+
+// Logs the return of held funds to their balance after a transfer definitely failed
+fn log_transfer_rollback(transfer: &PendingTransfer) {
+    if let Some((account, campaign_id)) = transfer_balance_account(&transfer.purpose) {
+        record_transaction(TransactionRecord {
+            memo: transfer_memo(transfer),
+            campaign_id,
+            ..new_transaction(
+                TransactionKind::Refund,
+                TransactionAccount::Transfer(transfer.id),
+                account,
+                transfer_debit(transfer),
+                &transfer.token,
+            )
+        });
+    }
+}
+
+// Records an opening entry for a balance that existed before the transaction log, so balances
+// recomputed from the log match the stored ones. Movements of the account logged since the upgrade
+// are already part of `balance`, so only what remains before them is opened; an account opened
+// before gets nothing more.
+fn open_balance(account: TransactionAccount, balance: NumTokens, token: &str, campaign_id: Option<String>) {
+    let key = account_index_key(&account);
+    let (mut logged_in, mut logged_out) = (NumTokens::from(0u64), NumTokens::from(0u64));
+    let ids: Vec<u64> = TRANSACTION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(key.clone(), 0)..)
+            .take_while(|entry| entry.key().0 == key)
+            .map(|entry| entry.key().1)
+            .collect()
+    });
+    for record in ids.into_iter().filter_map(|id| TRANSACTION_LOG.with(|log| log.borrow().get(id))) {
+        if record.token != token {
+            continue;
+        }
+        if account_index_key(&record.to) == key {
+            logged_in += record.amount.clone();
+        }
+        if account_index_key(&record.from) == key {
+            logged_out += record.amount;
+        }
+    }
+
+    let before_log = balance + logged_out;
+    if before_log > logged_in {
+        record_transaction(TransactionRecord {
+            memo: "opening balance".to_string(),
+            campaign_id,
+            ..new_transaction(TransactionKind::OpeningBalance, TransactionAccount::Canister, account, before_log - logged_in, token)
+        });
+    }
+}
+
+// Opens the funds held by transfers in flight when the transaction log was introduced
+fn open_pending_transfers() {
+    let transfers: Vec<PendingTransfer> = PENDING_TRANSFERS.with(|journal| {
+        journal.borrow().iter().map(|entry| entry.value()).collect()
+    });
+    for transfer in transfers {
+        if let Some((_, campaign_id)) = transfer_balance_account(&transfer.purpose) {
+            open_balance(TransactionAccount::Transfer(transfer.id), transfer_debit(&transfer), &transfer.token, campaign_id);
+        }
+    }
+}
+
+// Checks that the caller may see the transactions of an account. Controllers see every account;
+// canister-internal accounts are visible to them only.
+fn require_account_access(account: &TransactionAccount) -> Result<(), SoulboardError> {
+    let caller_principal = caller();
+    if is_controller(&caller_principal) {
+        return Ok(());
+    }
+    match account {
+        TransactionAccount::Campaign(campaign_id) => require_campaign_owner(campaign_id, "view").map(|_| ()),
+        TransactionAccount::Provider(provider_id) => require_provider_owner(provider_id, "view").map(|_| ()),
+        TransactionAccount::Escrow(booking_id) => {
+            let escrow = ESCROW_REGISTRY.with(|registry| registry.borrow().get(booking_id))
+                .ok_or_else(|| SoulboardError::not_found("escrow", booking_id))?;
+            require_campaign_owner(&escrow.campaign_id, "view")
+                .map(|_| ())
+                .or_else(|_| require_provider_owner(&escrow.provider_id, "view").map(|_| ()))
+                .map_err(|_| SoulboardError::Unauthorized(
+                    "You can only view escrows of your own campaigns or providers".to_string(),
+                ))
+        }
+        TransactionAccount::External(account) if account.owner == caller_principal => Ok(()),
+        _ => Err(SoulboardError::Unauthorized("Only controllers can view this account".to_string())),
+    }
+}
+
+// Page of the transactions filed under an index key, oldest first
+fn indexed_transactions(key: String, page: &PageRequest) -> Result<Page<TransactionRecord>, SoulboardError> {
+    let cursor = page_cursor::<u64>(page)?;
+    let ids = TRANSACTION_INDEX.with(|index| {
+        let index = index.borrow();
+        let start = match cursor {
+            Some(cursor) => RangeBound::Excluded(PairKey(key.clone(), cursor)),
+            None => RangeBound::Included(PairKey(key.clone(), 0)),
+        };
+        let matches = index
+            .range((start, RangeBound::Unbounded))
+            .take_while(|entry| entry.key().0 == key)
+            .map(|entry| (entry.key().1, entry.key().1));
+        paginate(matches, page.limit)
+    });
+    TRANSACTION_LOG.with(|log| {
+        let log = log.borrow();
+        Ok(Page {
+            items: ids.items.into_iter().filter_map(|id| log.get(id)).collect(),
+            next_cursor: ids.next_cursor,
+            total: ids.total,
+        })
+    })
+}
+
+// Transactions into or out of an account, oldest first
+#[ic_cdk::query]
+fn get_account_transactions(account: TransactionAccount, page: PageRequest) -> Result<Page<TransactionRecord>, SoulboardError> {
+    require_account_access(&account)?;
+    indexed_transactions(account_index_key(&account), &page)
+}
+
+// Every transaction that belongs to a campaign, including its escrows and payouts (only campaign
+// owner can see)
+#[ic_cdk::query]
+fn get_campaign_transactions(campaign_id: String, page: PageRequest) -> Result<Page<TransactionRecord>, SoulboardError> {
+    if !is_controller(&caller()) {
+        require_campaign_owner(&campaign_id, "view")?;
+    }
+    indexed_transactions(campaign_index_key(&campaign_id), &page)
+}
+
+// Recomputes the balance of an account in one token from the transaction log and compares it with
+// the stored campaign budget, provider earnings, escrow or held transfer
+#[ic_cdk::query]
+fn check_account_balance(account: TransactionAccount, token: String) -> Result<BalanceCheck, SoulboardError> {
+    require_account_access(&account)?;
+
+    let stored_balance = match &account {
+        TransactionAccount::Campaign(campaign_id) => {
+            let campaign = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(campaign_id))
+                .ok_or_else(|| SoulboardError::not_found("campaign", campaign_id))?;
+            if campaign.token == token { campaign.budget } else { NumTokens::from(0u64) }
+        }
+        TransactionAccount::Provider(provider_id) => {
+            let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(provider_id))
+                .ok_or_else(|| SoulboardError::not_found("provider", provider_id))?;
+            provider.total_earnings.get(&token).cloned().unwrap_or_default()
+        }
+        TransactionAccount::Escrow(booking_id) => {
+            let escrow = ESCROW_REGISTRY.with(|registry| registry.borrow().get(booking_id))
+                .ok_or_else(|| SoulboardError::not_found("escrow", booking_id))?;
+            if escrow.token == token { escrow.escrowed } else { NumTokens::from(0u64) }
+        }
+        // Held funds leave the account when the transfer settles and the journal entry is removed
+        TransactionAccount::Transfer(transfer_id) => PENDING_TRANSFERS
+            .with(|journal| journal.borrow().get(transfer_id))
+            .filter(|transfer| transfer.token == token && transfer_balance_account(&transfer.purpose).is_some())
+            .map(|transfer| transfer_debit(&transfer))
+            .unwrap_or_default(),
+        _ => return Err(SoulboardError::InvalidInput("The canister keeps no balance for this account".to_string())),
+    };
+
+    let key = account.key();
+    let mut credited = NumTokens::from(0u64);
+    let mut debited = NumTokens::from(0u64);
+    let index_key = account_index_key(&account);
+    let ids: Vec<u64> = TRANSACTION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(index_key.clone(), 0)..)
+            .take_while(|entry| entry.key().0 == index_key)
+            .map(|entry| entry.key().1)
+            .collect()
+    });
+    TRANSACTION_LOG.with(|log| {
+        let log = log.borrow();
+        for record in ids.into_iter().filter_map(|id| log.get(id)).filter(|record| record.token == token) {
+            if record.to.key() == key {
+                credited += record.amount.clone();
+            }
+            if record.from.key() == key {
+                debited += record.amount.clone();
+            }
+        }
+    });
+
+    let logged_balance = (credited >= debited).then(|| credited.clone() - debited.clone());
+    let consistent = logged_balance.as_ref() == Some(&stored_balance);
+    Ok(BalanceCheck { token, credited, debited, logged_balance, stored_balance, consistent })
+}
+
+// Restores the balance debited for a payout the ledger definitely did not execute
+fn roll_back_transfer(transfer: &PendingTransfer) {
+    log_transfer_rollback(transfer);
+    match &transfer.purpose {
+        TransferPurpose::ProviderWithdrawal { provider_id } => {
+            PROVIDER_REGISTRY.with(|registry| {
+                let mut registry_borrow = registry.borrow_mut();
+                if let Some(mut provider) = registry_borrow.get(provider_id) {
+                    *provider.total_earnings.entry(transfer.token.clone()).or_default() += transfer_debit(transfer);
+                    registry_borrow.insert(provider_id.clone(), provider);
+                }
+            });
+        }
+        TransferPurpose::CampaignWithdrawal { campaign_id } | TransferPurpose::CampaignCloseRefund { campaign_id } => {
+            CAMPAIGN_REGISTRY.with(|registry| {
+                let mut registry_borrow = registry.borrow_mut();
+                if let Some(mut campaign) = registry_borrow.get(campaign_id) {
+                    campaign.budget += transfer_debit(transfer);
+                    registry_borrow.insert(campaign_id.clone(), campaign);
+                }
+            });
+        }
+        // Nothing was credited for incoming funds yet
+        TransferPurpose::CampaignFunding { .. } | TransferPurpose::DepositSweep { .. } => {}
+    }
+}
+
+// Ledgers only deduplicate transactions whose created_at_time lies within their window (24 hours
+// for the ICP ledger). Retrying after the window could pay twice, so retries stop well before it
+// and older transfers are left for an operator to resolve.
+const TRANSFER_RETRY_WINDOW_NANOS: u64 = 20 * 60 * 60 * 1_000_000_000;
+const TRANSFER_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+fn start_transfer_retry_timer() {
+    ic_cdk_timers::set_timer_interval(TRANSFER_RETRY_INTERVAL, retry_pending_transfers);
+}
+
+// Resends journaled transfers whose outcome is unknown: uncertain ones, and pending ones whose
+// attempt was lost, e.g. because the canister was upgraded while awaiting the ledger.
+fn retry_pending_transfers() {
+    let now = time();
+    let retryable: Vec<u64> = PENDING_TRANSFERS.with(|journal| {
+        journal
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|transfer| now.saturating_sub(transfer.created_at_time) < TRANSFER_RETRY_WINDOW_NANOS)
+            .filter(|transfer| !transfer_in_flight(transfer.id))
+            .map(|transfer| transfer.id)
+            .collect()
+    });
+
+    for transfer_id in retryable {
+        ic_cdk::spawn(async move {
+            let _ = execute_journaled_transfer(transfer_id).await;
+        });
+    }
+}
+
+fn transfer_in_flight(transfer_id: u64) -> bool {
+    OPERATIONS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&transfer_guard_key(transfer_id)))
+}
+
+// Journaled transfers that are not currently awaiting the ledger: their outcome is unknown and
+// they are either waiting for the next retry or, past the retry window, for an operator
+// (controllers only).
+#[ic_cdk::query]
+fn get_stuck_transfers() -> Result<Vec<PendingTransfer>, SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can view stuck transfers".to_string()));
+    }
+
+    PENDING_TRANSFERS.with(|journal| {
+        Ok(journal
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|transfer| !transfer_in_flight(transfer.id))
+            .collect())
+    })
+}
+
+// Settles a stuck transfer by hand once an operator has looked it up on the ledger: pass the
+// block index if the transfer was executed, or None if it definitely was not (controllers only).
+#[ic_cdk::update]
+fn resolve_stuck_transfer(transfer_id: u64, block_index: Option<BlockIndex>) -> Result<(), SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can resolve stuck transfers".to_string()));
+    }
+    if transfer_in_flight(transfer_id) {
+        return Err(SoulboardError::Conflict(format!("transfer {} is awaiting the ledger", transfer_id)));
+    }
+    if !PENDING_TRANSFERS.with(|journal| journal.borrow().contains_key(&transfer_id)) {
+        return Err(SoulboardError::not_found("transfer", transfer_id));
+    }
+
+    let outcome = match block_index {
+        Some(block_index) => Ok(block_index),
+        None => Err(TransferFailure::Definite(SoulboardError::Conflict("Resolved as not executed by a controller".to_string()))),
+    };
+    settle_transfer(transfer_id, &outcome);
+    Ok(())
+}
+
+// Only the campaign owner can fund their campaign. The tokens are pulled from the caller's
+// account on the campaign token's ledger with ICRC-2 `transfer_from`, so the caller must first
+// `icrc2_approve` this canister for at least `amount` plus the ledger fee.
+#[ic_cdk::update]
+async fn fund_campaign(campaign_id: String, amount: NumTokens) -> Result<Receipt, SoulboardError> {
+    let caller_principal = caller();
+
+    if amount == 0u64 {
+        return Err(SoulboardError::InvalidInput("Funding amount must be greater than zero".to_string()));
+    }
+
+    let _guard = OperationGuard::acquire(vec![
+        principal_guard_key(&caller_principal),
+        campaign_guard_key(&campaign_id),
+    ])?;
+    
+    // First, verify the campaign exists and the caller is the owner
+    let token_symbol = CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only fund your own campaigns".to_string()));
+                }
+                if !campaign_is_open(&campaign.status) {
+                    return Err(SoulboardError::Conflict(format!("Campaign is {:?} and can no longer be funded", campaign.status)));
+                }
+                Ok(campaign.token)
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })?;
+    let token = token_config(&token_symbol)?;
+
+    // Pull the tokens from the caller's account into this canister. The fee is charged to the
+    // advertiser on top of the transfer, so the budget is credited exactly the requested amount
+    // once the transfer settles.
+    let transfer_id = journal_transfer(
+        TransferPurpose::CampaignFunding { campaign_id: campaign_id.clone() },
+        &token,
+        TransferSource::Allowance(principal_to_account(caller_principal)), // from - the caller, who approved this canister
+        principal_to_account(ic_cdk::api::id()), // to - this canister
+        amount,
+    );
+    match execute_journaled_transfer(transfer_id).await {
+        Ok(_) => stored_receipt(transfer_id),
+        Err(e) => Err(transfer_error(transfer_id, e)),
+    }
+}
+
+// Error returned by an endpoint whose journaled transfer did not complete
+fn transfer_error(transfer_id: u64, failure: TransferFailure) -> SoulboardError {
+    match failure {
+        TransferFailure::Definite(e) => e,
+        TransferFailure::Uncertain(e) => SoulboardError::TransferPending { transfer_id, msg: e.to_string() },
+    }
+}
+
+// Deterministic subaccount of this canister that receives plain transfers for a campaign: the
+// length of the campaign ID followed by its bytes, zero padded. Campaign IDs are generated by the
+// canister and always fit in the 31 bytes available.
+fn campaign_deposit_subaccount(campaign_id: &str) -> Subaccount {
+    let bytes = campaign_id.as_bytes();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+fn campaign_deposit_account(campaign_id: &str) -> Account {
+    Account {
+        owner: ic_cdk::api::id(),
+        subaccount: Some(campaign_deposit_subaccount(campaign_id)),
+    }
+}
+
+// Account that funds a campaign with a plain ICRC-1 transfer, for wallets that cannot approve
+// allowances (only campaign owner can see). Call notify_campaign_deposit after transferring.
+#[ic_cdk::query]
+fn get_campaign_deposit_account(campaign_id: String) -> Result<Account, SoulboardError> {
+    let caller_principal = caller();
+
+    CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only view your own campaign deposit account".to_string()));
+                }
+                Ok(campaign_deposit_account(&campaign_id))
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })
+}
+
+// Sweeps whatever sits in the campaign's deposit subaccount into the canister's main account and
+// credits it to the campaign budget. The sweep moves the entire balance, so a concurrent or
+// repeated notify finds nothing left to sweep (or fails at the ledger) and cannot credit the same
+// funds twice.
+#[ic_cdk::update]
+async fn notify_campaign_deposit(campaign_id: String) -> Result<Receipt, SoulboardError> {
+    let caller_principal = caller();
+
+    let token_symbol = CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only claim deposits for your own campaigns".to_string()));
+                }
+                if !campaign_is_open(&campaign.status) {
+                    return Err(SoulboardError::Conflict(format!("Campaign is {:?} and can no longer be funded", campaign.status)));
+                }
+                Ok(campaign.token)
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })?;
+    let token = token_config(&token_symbol)?;
+    let _guard = OperationGuard::acquire(vec![campaign_guard_key(&campaign_id)])?;
+
+    // Only the campaign token's ledger is swept; transfers on other ledgers are not credited
+    let deposit_account = campaign_deposit_account(&campaign_id);
+    let balance = ledger_balance_of(&token, deposit_account).await?;
+    let fee = token.fee.clone();
+    if balance <= fee {
+        return Err(SoulboardError::Conflict(format!("No deposit to claim: the deposit account holds {}", balance)));
+    }
+
+    // The sweep itself costs a ledger fee, which comes out of the deposit
+    let swept_amount = balance - fee;
+    let transfer_id = journal_transfer(
+        TransferPurpose::DepositSweep { campaign_id: campaign_id.clone() },
+        &token,
+        TransferSource::Canister(deposit_account.subaccount), // from - the campaign's deposit subaccount
+        principal_to_account(ic_cdk::api::id()), // to - this canister's main account
+        swept_amount,
+    );
+    match execute_journaled_transfer(transfer_id).await {
+        Ok(_) => stored_receipt(transfer_id),
+        Err(e) => Err(transfer_error(transfer_id, e)),
+    }
+}
+
+// Ledger deposits credited to a campaign (only campaign owner can see)
+#[ic_cdk::query]
+fn get_campaign_deposits(campaign_id: String) -> Result<Vec<Deposit>, SoulboardError> {
+    let caller_principal = caller();
+
+    CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only view your own campaign deposits".to_string()));
+                }
+                Ok(())
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })?;
+
+    DEPOSIT_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .filter_map(|entry| {
+                let deposit = entry.value();
+                if deposit.campaign_id == campaign_id {
+                    Some(deposit)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    })
+}
+
+// Provider can withdraw their earnings in one token with an actual ledger transfer. The provider
+// receives `amount`; the ledger fee is paid from the earnings on top of it.
+#[ic_cdk::update]
+async fn withdraw_provider_earnings(provider_id: String, token: String, amount: NumTokens) -> Result<Receipt, SoulboardError> {
+    let caller_principal = caller();
+    if amount == 0u64 {
+        return Err(SoulboardError::InvalidInput("Withdrawal amount must be greater than zero".to_string()));
+    }
+    let token = token_config(&token)?;
+    let debit = amount.clone() + token.fee.clone();
+    let _guard = OperationGuard::acquire(vec![
+        principal_guard_key(&caller_principal),
+        provider_guard_key(&provider_id),
+    ])?;
+    
+    // Verify the provider exists and the caller is the owner, then debit the earnings before the
+    // ledger call so the same balance cannot be paid out twice
+    PROVIDER_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+
+        match registry_borrow.get(&provider_id) {
+            Some(mut provider) => {
+                if provider.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only withdraw from your own provider account".to_string()));
+                }
+                let balance = provider.total_earnings.entry(token.symbol.clone()).or_default();
+                if *balance < debit {
+                    return Err(SoulboardError::InsufficientFunds { available: balance.clone(), requested: debit.clone() });
+                }
+                *balance -= debit.clone();
+                registry_borrow.insert(provider_id.clone(), provider);
+                Ok(())
+            }
+            None => Err(SoulboardError::not_found("provider", &provider_id)),
+        }
+    })?;
+
+    // Transfer tokens from this canister to the provider owner. A definite failure restores the
+    // debited earnings when the transfer settles; after an uncertain one they stay debited.
+    let transfer_id = journal_transfer(
+        TransferPurpose::ProviderWithdrawal { provider_id: provider_id.clone() },
+        &token,
+        TransferSource::Canister(None), // from - the canister's default account
+        principal_to_account(caller_principal), // to - provider's account
+        amount,
+    );
+    match execute_journaled_transfer(transfer_id).await {
+        Ok(_) => stored_receipt(transfer_id),
+        Err(e) => Err(transfer_error(transfer_id, e)),
+    }
+}
+
+// Only the campaign owner can withdraw funds from their campaign budget (emergency/unused funds).
+// The owner receives `amount`; the ledger fee is paid from the budget on top of it.
+#[ic_cdk::update]
+async fn withdraw_campaign_funds(campaign_id: String, amount: NumTokens) -> Result<Receipt, SoulboardError> {
+    let caller_principal = caller();
+    if amount == 0u64 {
+        return Err(SoulboardError::InvalidInput("Withdrawal amount must be greater than zero".to_string()));
+    }
+    let _guard = OperationGuard::acquire(vec![
+        principal_guard_key(&caller_principal),
+        campaign_guard_key(&campaign_id),
+    ])?;
+    
+    // Verify the campaign exists and the caller is the owner, then debit the budget before the
+    // ledger call so the same funds cannot be withdrawn twice
+    let token = CAMPAIGN_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        
+        match registry_borrow.get(&campaign_id) {
+            Some(mut campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only withdraw from your own campaigns".to_string()));
+                }
+                
+                let token = token_config(&campaign.token)?;
+                let debit = amount.clone() + token.fee.clone();
+                if campaign.budget < debit {
+                    return Err(SoulboardError::InsufficientFunds { available: campaign.budget, requested: debit });
+                }
+                
+                campaign.budget -= debit;
+                registry_borrow.insert(campaign_id.clone(), campaign);
+                Ok(token)
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })?;
+
+    // Transfer tokens from this canister to the campaign owner. A definite failure restores the
+    // debited budget when the transfer settles; after an uncertain one it stays debited.
+    let transfer_id = journal_transfer(
+        TransferPurpose::CampaignWithdrawal { campaign_id: campaign_id.clone() },
+        &token,
+        TransferSource::Canister(None), // from - the canister's default account
+        principal_to_account(caller_principal), // to - campaign owner's account
+        amount,
+    );
+    match execute_journaled_transfer(transfer_id).await {
+        Ok(_) => stored_receipt(transfer_id),
+        Err(e) => Err(transfer_error(transfer_id, e)),
+    }
+}
+
+// Only the campaign owner can close their campaign. A running campaign is cancelled; a completed
+// or cancelled one is archived. Closing is refused while one of the campaign's bookings is in
+// progress. Before the campaign is closed its pending plays are billed, the slots of its bookings
+// that are still waiting for attestation are settled and its upcoming bookings released; then
+// whatever is left of the budget is refunded to the owner through the ledger.
+// Closed campaigns are kept for their history, with the refund's block index. Returns the receipt
+// of the refund, or None when the budget left does not cover the ledger fee.
+#[ic_cdk::update]
+async fn close_campaign(campaign_id: String) -> Result<Option<Receipt>, SoulboardError> {
+    let caller_principal = caller();
+    let _guard = OperationGuard::acquire(vec![
+        principal_guard_key(&caller_principal),
+        campaign_guard_key(&campaign_id),
+    ])?;
+
+    let mut campaign = require_campaign_owner(&campaign_id, "close")?;
+    let next = match campaign.status {
+        CampaignStatus::Completed | CampaignStatus::Cancelled => CampaignStatus::Archived,
+        _ => CampaignStatus::Cancelled,
+    };
+    if !can_transition(&campaign.status, &next) {
+        return Err(SoulboardError::Conflict(format!("Campaign cannot go from {:?} to {:?}", campaign.status, next)));
+    }
+
+    let now = time();
+    let bookings = campaign_bookings(&campaign_id);
+    let running_booking = bookings.iter().find(|booking| {
+        booking.status == BookingStatus::Confirmed && booking.start_time <= now && booking.end_time > now
+    });
+    if let Some(booking) = running_booking {
+        return Err(SoulboardError::Conflict(format!(
+            "Booking {} is in progress; close the campaign after it ends at {}",
+            booking.id, booking.end_time
+        )));
+    }
+
+    // Impressions already played are charged before the budget is refunded. Each attempt bills
+    // one batch, so a close never does more than a timer run; while older plays are still
+    // unbilled the campaign stays open and the owner tries again.
+    run_billing_batch(BILLING_BATCH_SIZE);
+    let unbilled = PLAY_LOG.with(|log| log.borrow().len()) - BILLING_STATE.with(|state| state.borrow().get().next_play);
+    if unbilled > 0 {
+        return Err(SoulboardError::Conflict(format!(
+            "Billing is {} plays behind; close the campaign once they are billed",
+            unbilled
+        )));
+    }
+
+    // A closed campaign no longer runs on any provider
+    let linked_keys: Vec<String> = campaign_provider_links(&campaign_id)
+        .iter()
+        .map(|link| campaign_provider_key(&link.campaign_id, &link.provider_id))
+        .collect();
+    CAMPAIGN_PROVIDER_LINKS.with(|links| {
+        let mut links_borrow = links.borrow_mut();
+        for key in linked_keys {
+            links_borrow.remove(&key);
+        }
+    });
+    settle_started_slots(&bookings, now);
+    release_open_bookings(&campaign_id);
+
+    // Billing, settled slots and released bookings changed the budget, so the campaign is read again
+    campaign = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign_id))
+        .ok_or_else(|| SoulboardError::not_found("campaign", &campaign_id))?;
+    transition_campaign(&mut campaign, next)?;
+    // The ledger fee is paid from the budget, so a budget that does not cover it stays with the
+    // closed campaign
+    let token = token_config(&campaign.token)?;
+    if campaign.budget <= token.fee {
+        save_campaign(campaign);
+        return Ok(None);
+    }
+    let refund = campaign.budget.clone() - token.fee.clone();
+
+    // Debit the budget before the ledger call; a definite failure restores it when the transfer
+    // settles, and the owner can withdraw it from the closed campaign later
+    campaign.budget = NumTokens::from(0u64);
+    save_campaign(campaign);
+
+    let transfer_id = journal_transfer(
+        TransferPurpose::CampaignCloseRefund { campaign_id: campaign_id.clone() },
+        &token,
+        TransferSource::Canister(None), // from - the canister's default account
+        principal_to_account(caller_principal), // to - campaign owner's account
+        refund,
+    );
+    match execute_journaled_transfer(transfer_id).await {
+        Ok(_) => stored_receipt(transfer_id).map(Some),
+        // The campaign stays closed; a definitely failed refund is restored to its budget
+        Err(e) => Err(transfer_error(transfer_id, e)),
+    }
+}
+
+// Frees the locations a campaign has booked from now on
+fn release_open_bookings(campaign_id: &str) {
+    let now = time();
+    let open_bookings = campaign_bookings(campaign_id)
+        .into_iter()
+        .filter(|booking| booking.status != BookingStatus::Cancelled && booking.end_time > now);
+    for booking in open_bookings {
+        release_booking(booking);
+    }
+}
+
+// How long completed and cancelled campaigns stay visible as such before they are archived
+const ARCHIVE_DELAY_NANOS: u64 = 30 * 24 * SLOT_DURATION_NANOS;
+
+// Allowed campaign status transitions:
+//   Draft -> Scheduled            (schedule_campaign)
+//   Scheduled -> Scheduled        (schedule_campaign, rescheduling)
+//   Scheduled -> Active           (start time reached)
+//   Active <-> Paused             (pause_campaign / resume_campaign; budget used up)
+//   Active, Paused -> Completed   (end time reached)
+//   Draft, Scheduled, Active, Paused -> Cancelled   (close_campaign)
+//   Completed, Cancelled -> Archived                (close_campaign; ARCHIVE_DELAY_NANOS later)
+fn can_transition(from: &CampaignStatus, to: &CampaignStatus) -> bool {
+    use CampaignStatus::*;
+    matches!(
+        (from, to),
+        (Draft, Scheduled)
+            | (Scheduled, Scheduled)
+            | (Scheduled, Active)
+            | (Active, Paused)
+            | (Paused, Active)
+            | (Active, Completed)
+            | (Paused, Completed)
+            | (Draft, Cancelled)
+            | (Scheduled, Cancelled)
+            | (Active, Cancelled)
+            | (Paused, Cancelled)
+            | (Completed, Archived)
+            | (Cancelled, Archived)
+    )
+}
+
+fn transition_campaign(campaign: &mut Campaign, to: CampaignStatus) -> Result<(), SoulboardError> {
+    if !can_transition(&campaign.status, &to) {
+        return Err(SoulboardError::Conflict(format!("Campaign cannot go from {:?} to {:?}", campaign.status, to)));
+    }
+    if matches!(to, CampaignStatus::Completed | CampaignStatus::Cancelled) {
+        campaign.ended_at = Some(time());
+    }
+    campaign.status = to;
+    Ok(())
+}
+
+// Campaigns that have not ended yet can still be funded and booked
+fn campaign_is_open(status: &CampaignStatus) -> bool {
+    matches!(
+        status,
+        CampaignStatus::Draft | CampaignStatus::Scheduled | CampaignStatus::Active | CampaignStatus::Paused
+    )
+}
+
+// Sets when a draft or scheduled campaign runs (campaign owner only). Both times are aligned to
+// slots; the campaign becomes active at the start of its first slot and completes at the end.
+#[ic_cdk::update]
+fn schedule_campaign(campaign_id: String, start_time: u64, end_time: u64) -> Result<(), SoulboardError> {
+    let mut campaign = require_campaign_owner(&campaign_id, "schedule")?;
+
+    if !start_time.is_multiple_of(SLOT_DURATION_NANOS) || !end_time.is_multiple_of(SLOT_DURATION_NANOS) {
+        return Err(SoulboardError::InvalidInput("Campaign times must be aligned to one hour slots".to_string()));
+    }
+    if end_time <= start_time {
+        return Err(SoulboardError::InvalidInput("Campaign must end after it starts".to_string()));
+    }
+    let now = time();
+    if end_time <= now {
+        return Err(SoulboardError::InvalidInput("Campaign cannot end in the past".to_string()));
+    }
+
+    transition_campaign(&mut campaign, CampaignStatus::Scheduled)?;
+    campaign.start_time = Some(start_time);
+    campaign.end_time = Some(end_time);
+    advance_campaign_lifecycle(&mut campaign, now);
+    save_campaign(campaign);
+    Ok(())
+}
+
+// Stops an active campaign from being played (campaign owner only)
+#[ic_cdk::update]
+fn pause_campaign(campaign_id: String) -> Result<(), SoulboardError> {
+    let mut campaign = require_campaign_owner(&campaign_id, "pause")?;
+    transition_campaign(&mut campaign, CampaignStatus::Paused)?;
+    CAMPAIGN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(campaign_id, campaign);
+    });
+    Ok(())
+}
+
+// Lets a paused campaign be played again (campaign owner only)
+#[ic_cdk::update]
+fn resume_campaign(campaign_id: String) -> Result<(), SoulboardError> {
+    let mut campaign = require_campaign_owner(&campaign_id, "resume")?;
+    if campaign.budget == 0u64 {
+        return Err(SoulboardError::Conflict("Campaign budget is empty; fund the campaign before resuming it".to_string()));
+    }
+    transition_campaign(&mut campaign, CampaignStatus::Active)?;
+    CAMPAIGN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(campaign_id, campaign);
+    });
+    Ok(())
+}
+
+// Applies every transition that is due at `now`: scheduled campaigns start, running campaigns
+// complete at their end time and ended campaigns are archived after ARCHIVE_DELAY_NANOS
+fn advance_campaign_lifecycle(campaign: &mut Campaign, now: u64) -> bool {
+    let mut changed = false;
+    loop {
+        let next = match campaign.status {
+            CampaignStatus::Scheduled if campaign.start_time.is_some_and(|start| start <= now) => {
+                CampaignStatus::Active
+            }
+            CampaignStatus::Active | CampaignStatus::Paused if campaign.end_time.is_some_and(|end| end <= now) => {
+                CampaignStatus::Completed
+            }
+            CampaignStatus::Completed | CampaignStatus::Cancelled
+                if campaign.ended_at.is_some_and(|ended| ended + ARCHIVE_DELAY_NANOS <= now) =>
+            {
+                CampaignStatus::Archived
+            }
+            _ => return changed,
+        };
+        // Every transition above is allowed
+        let _ = transition_campaign(campaign, next);
+        changed = true;
+    }
+}
+
+// Time at which advance_campaign_lifecycle next changes the campaign, if anything is ahead of it
+fn next_transition_time(campaign: &Campaign) -> Option<u64> {
+    match campaign.status {
+        CampaignStatus::Scheduled => campaign.start_time,
+        CampaignStatus::Active | CampaignStatus::Paused => campaign.end_time,
+        CampaignStatus::Completed | CampaignStatus::Cancelled => {
+            campaign.ended_at.map(|ended| ended + ARCHIVE_DELAY_NANOS)
+        }
+        CampaignStatus::Draft | CampaignStatus::Archived => None,
+    }
+}
+
+// Stores a campaign whose status or times changed and moves it to its next transition in the
+// lifecycle index. Changes that leave both alone (budget, pausing) can write the registry directly.
+fn save_campaign(campaign: Campaign) {
+    let previous = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign.id));
+    CAMPAIGN_LIFECYCLE_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(due) = previous.as_ref().and_then(next_transition_time) {
+            index.remove(&PairKey(due, campaign.id.clone()));
+        }
+        if let Some(due) = next_transition_time(&campaign) {
+            index.insert(PairKey(due, campaign.id.clone()), ());
+        }
+    });
+    CAMPAIGN_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(campaign.id.clone(), campaign);
+    });
+}
+
+// Applies the transitions that are due, reading only the campaigns the lifecycle index lists
+// as due by now
+fn advance_campaign_lifecycles() {
+    let now = time();
+    let due: Vec<PairKey<u64, String>> = CAMPAIGN_LIFECYCLE_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(..PairKey(now.saturating_add(1), String::new()))
+            .map(|entry| entry.key().clone())
+            .collect()
+    });
+    for key in due {
+        CAMPAIGN_LIFECYCLE_INDEX.with(|index| {
+            index.borrow_mut().remove(&key);
+        });
+        let Some(mut campaign) = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&key.1)) else {
+            continue;
+        };
+        if !advance_campaign_lifecycle(&mut campaign, now) {
+            continue;
+        }
+        let completed = campaign.status == CampaignStatus::Completed;
+        let campaign_id = campaign.id.clone();
+        save_campaign(campaign);
+        // Slots booked past the end of a completed campaign are given back
+        if completed {
+            release_open_bookings(&campaign_id);
+        }
+    }
+}
+
+// Get provider earnings per token (only provider owner can see)
+#[ic_cdk::query]
+fn get_provider_earnings(provider_id: String) -> Result<BTreeMap<String, NumTokens>, SoulboardError> {
+    let caller_principal = caller();
+    
+    PROVIDER_REGISTRY.with(|registry| {
+        match registry.borrow().get(&provider_id) {
+            Some(provider) => {
+                if provider.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only view your own provider earnings".to_string()));
+                }
+                Ok(provider.total_earnings)
+            }
+            None => Err(SoulboardError::not_found("provider", &provider_id)),
+        }
+    })
+}
+
+// Get detailed earnings breakdown for a provider
+#[ic_cdk::query]
+fn get_provider_earnings_breakdown(provider_id: String) -> Result<Vec<ProviderEarnings>, SoulboardError> {
+    let caller_principal = caller();
+    
+    // Verify provider ownership
+    PROVIDER_REGISTRY.with(|registry| {
+        match registry.borrow().get(&provider_id) {
+            Some(provider) => {
+                if provider.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only view your own provider earnings".to_string()));
+                }
+                Ok(())
+            }
+            None => Err(SoulboardError::not_found("provider", &provider_id)),
+        }
+    })?;
+
+    // Get all earnings for this provider
+    EARNINGS_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .filter_map(|entry| {
+                let earnings = entry.value();
+                if earnings.provider_id == provider_id {
+                    Some(earnings)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    })
+}
+
+// Get campaign balance (only campaign owner can see)
+#[ic_cdk::query]
+fn get_campaign_balance(campaign_id: String) -> Result<NumTokens, SoulboardError> {
+    let caller_principal = caller();
+    
+    CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized("You can only view your own campaign balance".to_string()));
+                }
+                Ok(campaign.budget)
+            }
+            None => Err(SoulboardError::not_found("campaign", &campaign_id)),
+        }
+    })
+}
+
+fn campaign_provider_key(campaign_id: &str, provider_id: &str) -> String {
+    format!("{}:{}", campaign_id, provider_id)
+}
+
+// Links of a campaign, read through the "<campaign_id>:" key prefix
+fn campaign_provider_links(campaign_id: &str) -> Vec<CampaignProviderLink> {
+    let prefix = format!("{}:", campaign_id);
+    CAMPAIGN_PROVIDER_LINKS.with(|links| {
+        links
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+// Checks that the campaign exists and belongs to the caller
+fn require_campaign_owner(campaign_id: &str, action: &str) -> Result<Campaign, SoulboardError> {
+    let caller_principal = caller();
+
+    CAMPAIGN_REGISTRY.with(|registry| {
+        match registry.borrow().get(&campaign_id.to_string()) {
+            Some(campaign) => {
+                if campaign.owner != caller_principal {
+                    return Err(SoulboardError::Unauthorized(format!("You can only {} your own campaigns", action)));
+                }
+                Ok(campaign)
+            }
+            None => Err(SoulboardError::not_found("campaign", campaign_id)),
+        }
+    })
+}
+
+// Links a provider to one of the caller's campaigns, optionally narrowed to some of the
+// provider's locations. Linking an already linked provider adds the given locations.
+#[ic_cdk::update]
+fn add_provider(campaign_id: String, provider_id: String, location_ids: Vec<String>) -> Result<(), SoulboardError> {
+    require_campaign_owner(&campaign_id, "modify")?;
+
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+        .ok_or_else(|| SoulboardError::not_found("provider", &provider_id))?;
+    for location_id in &location_ids {
+        if !provider.locations.iter().any(|location| &location.id == location_id) {
+            return Err(SoulboardError::not_found("location", location_key(&provider_id, location_id)));
+        }
+    }
+
+    let key = campaign_provider_key(&campaign_id, &provider_id);
+    CAMPAIGN_PROVIDER_LINKS.with(|links| {
+        let mut links_borrow = links.borrow_mut();
+        let mut link = links_borrow.get(&key).unwrap_or_else(|| CampaignProviderLink {
+            campaign_id: campaign_id.clone(),
+            provider_id: provider_id.clone(),
+            location_ids: Vec::new(),
+            linked_at: time(),
+        });
+        for location_id in location_ids {
+            if !link.location_ids.contains(&location_id) {
+                link.location_ids.push(location_id);
+            }
+        }
+        links_borrow.insert(key, link);
+    });
+    Ok(())
+}
+
+// Unlinks a provider, and all of its locations, from one of the caller's campaigns
+#[ic_cdk::update]
+fn remove_provider(campaign_id: String, provider_id: String) -> Result<(), SoulboardError> {
+    require_campaign_owner(&campaign_id, "modify")?;
+
+    let key = campaign_provider_key(&campaign_id, &provider_id);
+    CAMPAIGN_PROVIDER_LINKS.with(|links| {
+        match links.borrow_mut().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(SoulboardError::InvalidInput("Provider is not linked to this campaign".to_string())),
+        }
+    })
+}
+
+// Locations are booked in whole slots of one hour
+const SLOT_DURATION_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// Longest range get_free_slots looks at in one call
+const MAX_FREE_SLOTS_RANGE_NANOS: u64 = 31 * 24 * SLOT_DURATION_NANOS;
+// Longest time range a single booking may hold a location for
+const MAX_BOOKING_NANOS: u64 = 31 * 24 * SLOT_DURATION_NANOS;
+
+// Location IDs are chosen by providers, so they are only unique together with the provider ID
+fn location_key(provider_id: &str, location_id: &str) -> String {
+    format!("{}/{}", provider_id, location_id)
+}
+
+// The confirmed booking of a location that starts last before `before`. Bookings of a location
+// never overlap, so this is also the one that ends last among them.
+fn last_booking_starting_before(location: &str, before: u64) -> Option<Booking> {
+    let booking_id = LOCATION_BOOKINGS.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(location.to_string(), 0)..PairKey(location.to_string(), before))
+            .next_back()
+            .map(|entry| entry.value())
+    })?;
+    BOOKING_REGISTRY.with(|registry| registry.borrow().get(&booking_id))
+}
+
+fn is_booked_at(location: &str, time: u64) -> bool {
+    match last_booking_starting_before(location, time + 1) {
+        Some(booking) => booking.end_time > time,
+        None => false,
+    }
+}
+
+// Books one of the provider's locations for a campaign (campaign owner only). The location has to
+// be linked to the campaign and free for the whole range, which spans at most MAX_BOOKING_NANOS.
+// Its cost, the location's base fee per slot, moves from the campaign budget into escrow. A
+// booking that costs nothing up front, such as one of a CPM-priced location, does not hold the
+// location until its provider accepts it with accept_booking.
+#[ic_cdk::update]
+fn book_location(
+    campaign_id: String,
+    provider_id: String,
+    location_id: String,
+    start_time: u64,
+    end_time: u64,
+) -> Result<String, SoulboardError> {
+    let campaign = require_campaign_owner(&campaign_id, "book locations for")?;
+    if !campaign_is_open(&campaign.status) {
+        return Err(SoulboardError::Conflict(format!("Campaign is {:?} and can no longer book locations", campaign.status)));
+    }
+
+    if !start_time.is_multiple_of(SLOT_DURATION_NANOS) || !end_time.is_multiple_of(SLOT_DURATION_NANOS) {
+        return Err(SoulboardError::InvalidInput("Booking times must be aligned to one hour slots".to_string()));
+    }
+    if end_time <= start_time {
+        return Err(SoulboardError::InvalidInput("Booking must end after it starts".to_string()));
+    }
+    if end_time - start_time > MAX_BOOKING_NANOS {
+        return Err(SoulboardError::InvalidInput(format!(
+            "A booking can span at most {} slots",
+            MAX_BOOKING_NANOS / SLOT_DURATION_NANOS
+        )));
+    }
+    let current_slot_start = time() / SLOT_DURATION_NANOS * SLOT_DURATION_NANOS;
+    if start_time < current_slot_start {
+        return Err(SoulboardError::InvalidInput("Booking cannot start in the past".to_string()));
+    }
+
+    let link = CAMPAIGN_PROVIDER_LINKS
+        .with(|links| links.borrow().get(&campaign_provider_key(&campaign_id, &provider_id)))
+        .ok_or_else(|| SoulboardError::InvalidInput("Provider is not linked to this campaign".to_string()))?;
+    if !link.location_ids.is_empty() && !link.location_ids.contains(&location_id) {
+        return Err(SoulboardError::InvalidInput("Location is not linked to this campaign".to_string()));
+    }
+
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+        .ok_or_else(|| SoulboardError::not_found("provider", &provider_id))?;
+    let (slot_fee, cpm_rate) = match provider.locations.iter().find(|location| location.id == location_id) {
+        Some(location) if location.status == LocationStatus::Inactive => {
+            return Err(SoulboardError::Conflict("Location is inactive".to_string()));
+        }
+        Some(location) => {
+            if location.token != campaign.token {
+                return Err(SoulboardError::InvalidInput(format!(
+                    "Location is priced in {} but the campaign budget is in {}",
+                    location.token, campaign.token
+                )));
+            }
+            // CPM-priced locations are billed per impression instead of per slot
+            match &location.cpm_rate {
+                Some(cpm_rate) => (NumTokens::from(0u64), Some(cpm_rate.clone())),
+                None => (location.base_fees.clone(), None),
+            }
+        }
+        None => return Err(SoulboardError::not_found("location", &location_id)),
+    };
+
+    let location = location_key(&provider_id, &location_id);
+    require_free_range(&location, start_time, end_time)?;
+
+    let slot_count = (end_time - start_time) / SLOT_DURATION_NANOS;
+    let cost = slot_fee.clone() * NumTokens::from(slot_count);
+    if campaign.budget < cost {
+        return Err(SoulboardError::InsufficientFunds { available: campaign.budget, requested: cost });
+    }
+    CAMPAIGN_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        if let Some(mut campaign) = registry_borrow.get(&campaign_id) {
+            campaign.budget -= cost.clone();
+            registry_borrow.insert(campaign_id.clone(), campaign);
+        }
+    });
+
+    let status = if cost == 0u64 { BookingStatus::Requested } else { BookingStatus::Confirmed };
+    let booking_id = format!("booking_{}", next_sequence_value(BOOKING_SEQUENCE));
+    let escrow = BookingEscrow {
+        booking_id: booking_id.clone(),
+        campaign_id: campaign_id.clone(),
+        provider_id: provider_id.clone(),
+        token: campaign.token,
+        slot_fee,
+        cpm_rate,
+        delivered_slots: Vec::new(),
+        played_slots: None,
+        refunded_slots: Vec::new(),
+        escrowed: cost,
+        released: NumTokens::from(0u64),
+        refunded: NumTokens::from(0u64),
+    };
+    record_transaction(TransactionRecord {
+        memo: format!("booking {}", booking_id),
+        campaign_id: Some(campaign_id.clone()),
+        ..new_transaction(
+            TransactionKind::Escrow,
+            TransactionAccount::Campaign(campaign_id.clone()),
+            TransactionAccount::Escrow(booking_id.clone()),
+            escrow.escrowed.clone(),
+            &escrow.token,
+        )
+    });
+    ESCROW_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(booking_id.clone(), escrow);
+    });
+    let booking = Booking {
+        id: booking_id.clone(),
+        campaign_id,
+        provider_id,
+        location_id,
+        start_time,
+        end_time,
+        status,
+        created_at: time(),
+    };
+    CAMPAIGN_BOOKINGS.with(|index| {
+        index.borrow_mut().insert(PairKey(booking.campaign_id.clone(), booking_id.clone()), ());
+    });
+    if booking.status == BookingStatus::Confirmed {
+        confirm_booking(booking);
+    } else {
+        BOOKING_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(booking_id.clone(), booking);
+        });
+    }
+    Ok(booking_id)
+}
+
+fn require_free_range(location: &str, start_time: u64, end_time: u64) -> Result<(), SoulboardError> {
+    match last_booking_starting_before(location, end_time) {
+        Some(booking) if booking.end_time > start_time => {
+            Err(SoulboardError::Conflict(format!("Location is already booked by {} in this time range", booking.id)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Stores a booking as confirmed and has it hold its location's time range
+fn confirm_booking(mut booking: Booking) {
+    booking.status = BookingStatus::Confirmed;
+    let location = location_key(&booking.provider_id, &booking.location_id);
+    LOCATION_BOOKINGS.with(|index| {
+        index.borrow_mut().insert(PairKey(location, booking.start_time), booking.id.clone());
+    });
+    add_booking_boundaries(&booking);
+    let (provider_id, location_id) = (booking.provider_id.clone(), booking.location_id.clone());
+    BOOKING_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(booking.id.clone(), booking);
+    });
+    refresh_location_status(&provider_id, &location_id);
+}
+
+// Has the slot tick refresh the booking's location when the booking starts and when it ends
+fn add_booking_boundaries(booking: &Booking) {
+    BOOKING_BOUNDARIES.with(|index| {
+        let mut index = index.borrow_mut();
+        for boundary in [booking.start_time, booking.end_time] {
+            index.insert(
+                PairKey(boundary, booking.id.clone()),
+                PairKey(booking.provider_id.clone(), booking.location_id.clone()),
+            );
+        }
+    });
+}
+
+fn requested_booking_for_provider(booking_id: &str, action: &str) -> Result<Booking, SoulboardError> {
+    let booking = BOOKING_REGISTRY.with(|registry| registry.borrow().get(&booking_id.to_string()))
+        .ok_or_else(|| SoulboardError::not_found("booking", booking_id))?;
+    require_provider_owner(&booking.provider_id, action)?;
+    if booking.status != BookingStatus::Requested {
+        return Err(SoulboardError::Conflict(format!("Booking is {:?}, not awaiting acceptance", booking.status)));
+    }
+    Ok(booking)
+}
+
+// Accepts a booking request on one of the provider's locations (provider owner only). The
+// location has to still be free, and the booking must not have started yet.
+#[ic_cdk::update]
+fn accept_booking(booking_id: String) -> Result<(), SoulboardError> {
+    let booking = requested_booking_for_provider(&booking_id, "accept bookings of")?;
+    if booking.start_time < time() {
+        return Err(SoulboardError::Conflict("Booking request has expired".to_string()));
+    }
+    require_free_range(&location_key(&booking.provider_id, &booking.location_id), booking.start_time, booking.end_time)?;
+    confirm_booking(booking);
+    Ok(())
+}
+
+// Declines a booking request on one of the provider's locations (provider owner only)
+#[ic_cdk::update]
+fn decline_booking(booking_id: String) -> Result<(), SoulboardError> {
+    let booking = requested_booking_for_provider(&booking_id, "decline bookings of")?;
+    release_booking(booking);
+    Ok(())
+}
+
+// Booking requests waiting for the provider to accept them (provider owner only)
+#[ic_cdk::query]
+fn get_booking_requests(provider_id: String) -> Result<Vec<Booking>, SoulboardError> {
+    require_provider_owner(&provider_id, "view booking requests of")?;
+    BOOKING_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|booking| booking.provider_id == provider_id && booking.status == BookingStatus::Requested)
+            .collect())
+    })
+}
+
+// Cancels a booking that has not ended yet (campaign owner only). Slots that have not started are
+// refunded; earlier slots stay in escrow until they are attested or their attestation window closes.
+#[ic_cdk::update]
+fn cancel_booking(booking_id: String) -> Result<(), SoulboardError> {
+    let booking = BOOKING_REGISTRY.with(|registry| registry.borrow().get(&booking_id))
+        .ok_or_else(|| SoulboardError::not_found("booking", &booking_id))?;
+    require_campaign_owner(&booking.campaign_id, "cancel bookings of")?;
+
+    if booking.status == BookingStatus::Cancelled {
+        return Err(SoulboardError::Conflict("Booking is already cancelled".to_string()));
+    }
+    if booking.end_time <= time() {
+        return Err(SoulboardError::Conflict("Booking has already ended".to_string()));
+    }
+
+    release_booking(booking);
+    Ok(())
+}
+
+// Marks a booking cancelled, frees its time range and refunds its slots that have not started
+fn release_booking(mut booking: Booking) {
+    if let Some(mut escrow) = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&booking.id)) {
+        let now = time();
+        let upcoming: Vec<u64> = booking_slots(&booking)
+            .into_iter()
+            .filter(|slot| *slot >= now && !slot_is_settled(&escrow, *slot))
+            .collect();
+        refund_escrow_slots(&mut escrow, upcoming);
+        ESCROW_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(booking.id.clone(), escrow);
+        });
+    }
+
+    // A request never held the range, which may belong to another booking by now
+    let held_range = booking.status == BookingStatus::Confirmed;
+    if held_range {
+        LOCATION_BOOKINGS.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&PairKey(location_key(&booking.provider_id, &booking.location_id), booking.start_time));
+        });
+        BOOKING_BOUNDARIES.with(|index| {
+            let mut index = index.borrow_mut();
+            index.remove(&PairKey(booking.start_time, booking.id.clone()));
+            index.remove(&PairKey(booking.end_time, booking.id.clone()));
+        });
+    }
+    booking.status = BookingStatus::Cancelled;
+    let (provider_id, location_id) = (booking.provider_id.clone(), booking.location_id.clone());
+    BOOKING_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(booking.id.clone(), booking);
+    });
+    if held_range {
+        refresh_location_status(&provider_id, &location_id);
+    }
+}
+
+// Bookings of a campaign (only campaign owner can see)
+#[ic_cdk::query]
+fn get_campaign_bookings(campaign_id: String) -> Result<Vec<Booking>, SoulboardError> {
+    require_campaign_owner(&campaign_id, "view")?;
+    Ok(campaign_bookings(&campaign_id))
+}
+
+// Bookings of a campaign, read through the campaign bookings index
+fn campaign_bookings(campaign_id: &str) -> Vec<Booking> {
+    let booking_ids: Vec<String> = CAMPAIGN_BOOKINGS.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(campaign_id.to_string(), String::new())..)
+            .take_while(|entry| entry.key().0 == campaign_id)
+            .map(|entry| entry.key().1.clone())
+            .collect()
+    });
+    BOOKING_REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        booking_ids.iter().filter_map(|booking_id| registry.get(booking_id)).collect()
+    })
+}
+
+// Free time ranges of a location between `from` and `to`, rounded outwards to whole slots.
+// Adjacent free slots are merged into one range.
+#[ic_cdk::query]
+fn get_free_slots(provider_id: String, location_id: String, from: u64, to: u64) -> Result<Vec<TimeRange>, SoulboardError> {
+    let from = from / SLOT_DURATION_NANOS * SLOT_DURATION_NANOS;
+    let to = to.div_ceil(SLOT_DURATION_NANOS) * SLOT_DURATION_NANOS;
+    if to <= from {
+        return Err(SoulboardError::InvalidInput("Range must end after it starts".to_string()));
+    }
+    if to - from > MAX_FREE_SLOTS_RANGE_NANOS {
+        return Err(SoulboardError::InvalidInput("Range must not be longer than 31 days".to_string()));
+    }
+
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+        .ok_or_else(|| SoulboardError::not_found("provider", &provider_id))?;
+    match provider.locations.iter().find(|location| location.id == location_id) {
+        Some(location) if location.status == LocationStatus::Inactive => return Ok(Vec::new()),
+        Some(_) => {}
+        None => return Err(SoulboardError::not_found("location", &location_id)),
+    }
+
+    // Bookings overlapping the range: the one starting last before it, then the ones inside it
+    let location = location_key(&provider_id, &location_id);
+    let mut booked: Vec<(u64, u64)> = last_booking_starting_before(&location, from)
+        .map(|booking| (booking.start_time, booking.end_time))
+        .into_iter()
+        .collect();
+    let booking_ids: Vec<String> = LOCATION_BOOKINGS.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(location.clone(), from)..PairKey(location.clone(), to))
+            .map(|entry| entry.value())
+            .collect()
+    });
+    BOOKING_REGISTRY.with(|registry| {
+        let registry_borrow = registry.borrow();
+        for booking_id in booking_ids {
+            if let Some(booking) = registry_borrow.get(&booking_id) {
+                booked.push((booking.start_time, booking.end_time));
+            }
+        }
+    });
+
+    let mut free = Vec::new();
+    let mut cursor = from;
+    for (start_time, end_time) in booked {
+        if start_time > cursor {
+            free.push(TimeRange { start_time: cursor, end_time: start_time.min(to) });
+        }
+        cursor = cursor.max(end_time);
+        if cursor >= to {
+            break;
+        }
+    }
+    if cursor < to {
+        free.push(TimeRange { start_time: cursor, end_time: to });
+    }
+    Ok(free)
+}
+
+// Sets a location that is not inactive to Booked or Active, depending on whether a booking
+// covers the current time
+fn refresh_location_status(provider_id: &str, location_id: &str) {
+    let now = time();
+    PROVIDER_REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let Some(mut provider) = registry.get(&provider_id.to_string()) else {
+            return;
+        };
+        let Some(location) = provider.locations.iter_mut().find(|location| location.id == location_id) else {
+            return;
+        };
+        if location.status == LocationStatus::Inactive {
+            return;
+        }
+        let status = if is_booked_at(&location_key(provider_id, location_id), now) {
+            LocationStatus::Booked
+        } else {
+            LocationStatus::Active
+        };
+        if location.status != status {
+            location.status = status;
+            registry.insert(provider_id.to_string(), provider);
+        }
+    });
+}
+
+// Refreshes the locations whose bookings started or ended since the last slot tick
+fn refresh_passed_booking_boundaries() {
+    let now = time();
+    let passed: Vec<(PairKey<u64, String>, PairKey<String, String>)> = BOOKING_BOUNDARIES.with(|index| {
+        index
+            .borrow()
+            .range(..PairKey(now.saturating_add(1), String::new()))
+            .map(|entry| entry.into_pair())
+            .collect()
+    });
+    let mut locations = BTreeSet::new();
+    for (key, PairKey(provider_id, location_id)) in passed {
+        BOOKING_BOUNDARIES.with(|index| {
+            index.borrow_mut().remove(&key);
+        });
+        locations.insert((provider_id, location_id));
+    }
+    for (provider_id, location_id) in locations {
+        refresh_location_status(&provider_id, &location_id);
+    }
+}
+
+// Bookings and campaign schedules start and end on slot boundaries, so campaign and location
+// statuses are advanced, and escrowed slots past their attestation window refunded, right after
+// each boundary. Timers do not survive upgrades; post_upgrade starts them again.
+fn start_slot_timer() {
+    ic_cdk_timers::set_timer(Duration::ZERO, run_slot_tick);
+    // A tick that traps has its own changes rolled back, timers it set included, so the ticks run
+    // on an interval the timer library re-arms itself. The interval is set up by a timer that does
+    // nothing else, once the next boundary is reached.
+    let now = time();
+    let next_boundary = (now / SLOT_DURATION_NANOS + 1) * SLOT_DURATION_NANOS;
+    ic_cdk_timers::set_timer(Duration::from_nanos(next_boundary - now), || {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_slot_tick);
+        ic_cdk_timers::set_timer_interval(Duration::from_nanos(SLOT_DURATION_NANOS), run_slot_tick);
+    });
+}
+
+fn run_slot_tick() {
+    advance_campaign_lifecycles();
+    refresh_passed_booking_boundaries();
+    refund_unattested_slots();
+}
+
+// How long after a slot ends its delivery can be attested. Slots that are not attested by then are
+// refunded to the campaign.
+const ATTESTATION_WINDOW_NANOS: u64 = 7 * 24 * SLOT_DURATION_NANOS;
+
+// Start times of all slots of a booking
+fn booking_slots(booking: &Booking) -> Vec<u64> {
+    let slot_count = (booking.end_time - booking.start_time) / SLOT_DURATION_NANOS;
+    (0..slot_count)
+        .map(|slot| booking.start_time + slot * SLOT_DURATION_NANOS)
+        .collect()
+}
+
+fn slot_is_settled(escrow: &BookingEscrow, slot: u64) -> bool {
+    escrow.delivered_slots.contains(&slot) || escrow.refunded_slots.contains(&slot)
+}
+
+// Moves the fees of the given slots from the escrow back to the campaign budget
+fn refund_escrow_slots(escrow: &mut BookingEscrow, slots: Vec<u64>) {
+    if slots.is_empty() {
+        return;
+    }
+    let amount = escrow.slot_fee.clone() * NumTokens::from(slots.len());
+    escrow.refunded_slots.extend(slots);
+    escrow.escrowed -= amount.clone();
+    escrow.refunded += amount.clone();
+    record_transaction(TransactionRecord {
+        memo: format!("booking {}", escrow.booking_id),
+        campaign_id: Some(escrow.campaign_id.clone()),
+        ..new_transaction(
+            TransactionKind::Refund,
+            TransactionAccount::Escrow(escrow.booking_id.clone()),
+            TransactionAccount::Campaign(escrow.campaign_id.clone()),
+            amount.clone(),
+            &escrow.token,
+        )
+    });
+
+    CAMPAIGN_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        if let Some(mut campaign) = registry_borrow.get(&escrow.campaign_id) {
+            campaign.budget += amount;
+            registry_borrow.insert(escrow.campaign_id.clone(), campaign);
+        }
+    });
+}
+
+// Adds an amount to a provider's withdrawable earnings and to their earnings record for the
+// campaign it was earned from
+fn credit_provider_earnings(provider_id: &str, campaign_id: &str, token: &str, amount: NumTokens) {
+    PROVIDER_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        if let Some(mut provider) = registry_borrow.get(&provider_id.to_string()) {
+            *provider.total_earnings.entry(token.to_string()).or_default() += amount.clone();
+            registry_borrow.insert(provider_id.to_string(), provider);
+        }
+    });
+
+    // Update or create earnings record
+    let earnings_key = format!("{}:{}", provider_id, campaign_id);
+    EARNINGS_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        match registry_borrow.get(&earnings_key) {
+            Some(mut earnings) => {
+                earnings.total_earned += amount;
+                registry_borrow.insert(earnings_key, earnings);
+            }
+            None => {
+                let new_earnings = ProviderEarnings {
+                    provider_id: provider_id.to_string(),
+                    campaign_id: campaign_id.to_string(),
+                    total_earned: amount,
+                    token: token.to_string(),
+                    last_withdrawal: None,
+                };
+                registry_borrow.insert(earnings_key, new_earnings);
+            }
+        }
+    });
+}
+
+// Attests that slots of a booking were delivered, which releases their fees from escrow to the
+// provider's earnings. The campaign owner can confirm any slot. The provider owner can only attest
+// slots in which a registered device of the location reported a signed play of the campaign, so a
+// provider cannot pay itself for slots nobody saw played. Slots can be attested once they have
+// ended and until the attestation window closes, including the slots of a cancelled booking that
+// started before it was cancelled.
+#[ic_cdk::update]
+fn attest_delivery(booking_id: String, slot_start_times: Vec<u64>) -> Result<NumTokens, SoulboardError> {
+    let caller_principal = caller();
+
+    let booking = BOOKING_REGISTRY.with(|registry| registry.borrow().get(&booking_id))
+        .ok_or_else(|| SoulboardError::not_found("booking", &booking_id))?;
+    let campaign_owner = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&booking.campaign_id))
+        .map(|campaign| campaign.owner);
+    let provider_owner = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&booking.provider_id))
+        .map(|provider| provider.owner);
+    let confirmed_by_advertiser = campaign_owner == Some(caller_principal);
+    if !confirmed_by_advertiser && provider_owner != Some(caller_principal) {
+        return Err(SoulboardError::Unauthorized("You can only attest bookings of your own campaigns or providers".to_string()));
+    }
+    // Cancelling refunds only the slots that had not started, so the escrow, not the booking
+    // status, tells which slots of a cancelled booking can still be attested
+    if booking.status == BookingStatus::Requested {
+        return Err(SoulboardError::Conflict(format!("Booking is {:?}", booking.status)));
+    }
+    let mut escrow = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&booking_id))
+        .ok_or_else(|| SoulboardError::not_found("escrow", &booking_id))?;
+
+    let now = time();
+    let slots = booking_slots(&booking);
+    let mut delivered = Vec::new();
+    for slot in slot_start_times {
+        if !slots.contains(&slot) {
+            return Err(SoulboardError::InvalidInput(format!("Slot {} is not part of booking {}", slot, booking_id)));
+        }
+        if slot + SLOT_DURATION_NANOS > now {
+            return Err(SoulboardError::Conflict(format!("Slot {} has not ended yet", slot)));
+        }
+        if slot + SLOT_DURATION_NANOS + ATTESTATION_WINDOW_NANOS <= now {
+            return Err(SoulboardError::Conflict(format!("Attestation window for slot {} has closed", slot)));
+        }
+        if slot_is_settled(&escrow, slot) || delivered.contains(&slot) {
+            return Err(SoulboardError::Conflict(format!("Slot {} is already settled", slot)));
+        }
+        if !confirmed_by_advertiser && !escrow.played_slots.iter().flatten().any(|played| *played == slot) {
+            return Err(SoulboardError::Conflict(format!(
+                "No signed play was reported in slot {}; only the campaign owner can confirm its delivery",
+                slot
+            )));
+        }
+        delivered.push(slot);
+    }
+
+    let amount = release_escrow_slots(&mut escrow, delivered);
+    ESCROW_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(booking_id, escrow);
+    });
+
+    Ok(amount)
+}
+
+// Moves the fees of the given delivered slots from the escrow to the provider's earnings
+fn release_escrow_slots(escrow: &mut BookingEscrow, slots: Vec<u64>) -> NumTokens {
+    let amount = escrow.slot_fee.clone() * NumTokens::from(slots.len());
+    escrow.delivered_slots.extend(slots);
+    escrow.escrowed -= amount.clone();
+    escrow.released += amount.clone();
+    credit_provider_earnings(&escrow.provider_id, &escrow.campaign_id, &escrow.token, amount.clone());
+    record_transaction(TransactionRecord {
+        memo: format!("booking {}", escrow.booking_id),
+        campaign_id: Some(escrow.campaign_id.clone()),
+        ..new_transaction(
+            TransactionKind::Release,
+            TransactionAccount::Escrow(escrow.booking_id.clone()),
+            TransactionAccount::Provider(escrow.provider_id.clone()),
+            amount.clone(),
+            &escrow.token,
+        )
+    });
+    amount
+}
+
+// Settles the slots of a closing campaign's bookings that started before `now` and are still in
+// escrow. Nobody can attest them once the campaign is closed, so they are settled the way the
+// provider could still have attested them: slots with a signed play are released to the provider
+// and the others are refunded to the budget.
+fn settle_started_slots(bookings: &[Booking], now: u64) {
+    for booking in bookings {
+        let Some(mut escrow) = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&booking.id)) else {
+            continue;
+        };
+        if escrow.escrowed == 0u64 {
+            continue;
+        }
+        let (played, unplayed): (Vec<u64>, Vec<u64>) = booking_slots(booking)
+            .into_iter()
+            .filter(|slot| *slot < now && !slot_is_settled(&escrow, *slot))
+            .partition(|slot| escrow.played_slots.iter().flatten().any(|played| played == slot));
+        if !played.is_empty() {
+            release_escrow_slots(&mut escrow, played);
+        }
+        refund_escrow_slots(&mut escrow, unplayed);
+        ESCROW_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(booking.id.clone(), escrow);
+        });
+    }
+}
+
+// Refunds every escrowed slot whose attestation window has closed
+fn refund_unattested_slots() {
+    let now = time();
+    let open_escrows: Vec<BookingEscrow> = ESCROW_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|escrow| escrow.escrowed > 0u64)
+            .collect()
+    });
+
+    for mut escrow in open_escrows {
+        let Some(booking) = BOOKING_REGISTRY.with(|registry| registry.borrow().get(&escrow.booking_id)) else {
+            continue;
+        };
+        let expired: Vec<u64> = booking_slots(&booking)
+            .into_iter()
+            .filter(|slot| slot + SLOT_DURATION_NANOS + ATTESTATION_WINDOW_NANOS <= now)
+            .filter(|slot| !slot_is_settled(&escrow, *slot))
+            .collect();
+        if expired.is_empty() {
+            continue;
+        }
+        refund_escrow_slots(&mut escrow, expired);
+        ESCROW_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(escrow.booking_id.clone(), escrow);
+        });
+    }
+}
+
+// Escrow of a booking (only the campaign owner and the provider owner can see)
+#[ic_cdk::query]
+fn get_booking_escrow(booking_id: String) -> Result<BookingEscrow, SoulboardError> {
+    let caller_principal = caller();
+
+    let escrow = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&booking_id))
+        .ok_or_else(|| SoulboardError::not_found("escrow", &booking_id))?;
+    let campaign_owner = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&escrow.campaign_id))
+        .map(|campaign| campaign.owner);
+    let provider_owner = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&escrow.provider_id))
+        .map(|provider| provider.owner);
+    if campaign_owner != Some(caller_principal) && provider_owner != Some(caller_principal) {
+        return Err(SoulboardError::Unauthorized("You can only view escrows of your own campaigns or providers".to_string()));
+    }
+    Ok(escrow)
+}
+
+// Escrows of all bookings of a campaign (only campaign owner can see)
+#[ic_cdk::query]
+fn get_campaign_escrows(campaign_id: String) -> Result<Vec<BookingEscrow>, SoulboardError> {
+    require_campaign_owner(&campaign_id, "view")?;
+
+    ESCROW_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|escrow| escrow.campaign_id == campaign_id)
+            .collect())
+    })
+}
+
+// Most play events a device can report in one call
+const MAX_PLAY_REPORT_SIZE: usize = 500;
+// Prefix of every signed play message, so play signatures cannot be reused for anything else
+const PLAY_SIGNATURE_DOMAIN: &[u8] = b"soulboard-play-v1";
+
+// Checks that the provider exists, belongs to the caller and has the location
+fn require_provider_location(provider_id: &str, location_id: &str, action: &str) -> Result<Provider, SoulboardError> {
+    let provider = require_provider_owner(provider_id, action)?;
+    if !provider.locations.iter().any(|location| location.id == location_id) {
+        return Err(SoulboardError::not_found("location", location_id));
+    }
+    Ok(provider)
+}
+
+// Registers a device principal and its Ed25519 public key for one of the caller's locations
+// (provider owner only)
+#[ic_cdk::update]
+fn register_device(provider_id: String, location_id: String, device: Principal, public_key: Vec<u8>) -> Result<(), SoulboardError> {
+    require_provider_location(&provider_id, &location_id, "register devices for")?;
+    parse_device_key(&public_key).map_err(SoulboardError::InvalidInput)?;
+
+    DEVICE_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        if let Some(existing) = registry_borrow.get(&device) {
+            return Err(SoulboardError::Conflict(format!(
+                "Device is already registered for location {} of provider {}",
+                existing.location_id, existing.provider_id
+            )));
+        }
+        // A device registered before continues its counter sequence
+        let last_counter = RETIRED_DEVICE_COUNTERS.with(|counters| counters.borrow_mut().remove(&device)).unwrap_or(0);
+        registry_borrow.insert(device, Device {
+            principal: device,
+            provider_id,
+            location_id,
+            registered_at: time(),
+            public_key: Some(public_key),
+            last_counter,
+            invalid_attestations: 0,
+        });
+        Ok(())
+    })
+}
+
+// Replaces the Ed25519 public key of one of the caller's devices (provider owner only). The device
+// keeps its counter, so plays signed under an earlier key cannot be replayed if that key returns.
+#[ic_cdk::update]
+fn set_device_key(device: Principal, public_key: Vec<u8>) -> Result<(), SoulboardError> {
+    let mut registered = DEVICE_REGISTRY.with(|registry| registry.borrow().get(&device))
+        .ok_or_else(|| SoulboardError::not_found("device", device))?;
+    require_provider_location(&registered.provider_id, &registered.location_id, "manage devices of")?;
+    parse_device_key(&public_key).map_err(SoulboardError::InvalidInput)?;
+
+    registered.public_key = Some(public_key);
+    DEVICE_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(device, registered);
+    });
+    Ok(())
+}
+
+fn parse_device_key(public_key: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes: &[u8; PUBLIC_KEY_LENGTH] = public_key
+        .try_into()
+        .map_err(|_| format!("Device key must be a {} byte Ed25519 public key", PUBLIC_KEY_LENGTH))?;
+    VerifyingKey::from_bytes(bytes).map_err(|_| "Device key is not a valid Ed25519 public key".to_string())
+}
+
+// Bytes a device signs for a play: a domain tag followed by the device principal, the play fields
+// and the counter. Variable-length fields are prefixed with their length so that no two plays
+// share an encoding.
+fn play_signing_message(device: &Principal, event: &PlayEvent) -> Vec<u8> {
+    let mut message = PLAY_SIGNATURE_DOMAIN.to_vec();
+    for field in [device.as_slice(), event.campaign_id.as_bytes(), event.creative_hash.as_bytes()] {
+        message.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        message.extend_from_slice(field);
+    }
+    message.extend_from_slice(&event.timestamp.to_be_bytes());
+    message.extend_from_slice(&event.duration_ms.to_be_bytes());
+    message.extend_from_slice(&event.counter.to_be_bytes());
+    message
+}
+
+// Checks the device's signature over a play. Pure, so it runs the same in and outside a canister.
+fn verify_play_signature(public_key: &[u8], device: &Principal, event: &PlayEvent) -> Result<(), String> {
+    let key = parse_device_key(public_key)?;
+    let signature = Signature::from_slice(&event.signature)
+        .map_err(|_| "Play signature is not a valid Ed25519 signature".to_string())?;
+    key.verify_strict(&play_signing_message(device, event), &signature)
+        .map_err(|_| "Play signature does not verify".to_string())
+}
+
+// Checks the attestation of a play against the device's key and last counter
+fn verify_play_attestation(device: &Device, event: &PlayEvent) -> Result<(), String> {
+    let public_key = device.public_key.as_ref()
+        .ok_or_else(|| "Device has no attestation key registered".to_string())?;
+    verify_play_signature(public_key, &device.principal, event)?;
+    if event.counter <= device.last_counter {
+        return Err(format!(
+            "Replayed or out-of-order counter {} (last accepted counter is {})",
+            event.counter, device.last_counter
+        ));
+    }
+    Ok(())
+}
+
+// Devices with at least one invalid attestation, a sign of tampering (controllers only)
+#[ic_cdk::query]
+fn get_flagged_devices() -> Result<Vec<Device>, SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can view flagged devices".to_string()));
+    }
+
+    DEVICE_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|device| device.invalid_attestations > 0)
+            .collect())
+    })
+}
+
+// Removes a device from the caller's location (provider owner only)
+#[ic_cdk::update]
+fn remove_device(device: Principal) -> Result<(), SoulboardError> {
+    let registered = DEVICE_REGISTRY.with(|registry| registry.borrow().get(&device))
+        .ok_or_else(|| SoulboardError::not_found("device", device))?;
+    require_provider_location(&registered.provider_id, &registered.location_id, "remove devices from")?;
+
+    retire_device(&registered);
+    Ok(())
+}
+
+// Removes a device and keeps its last counter, so plays signed before the removal cannot be
+// replayed if the principal is registered again
+fn retire_device(device: &Device) {
+    DEVICE_REGISTRY.with(|registry| {
+        registry.borrow_mut().remove(&device.principal);
+    });
+    RETIRED_DEVICE_COUNTERS.with(|counters| {
+        counters.borrow_mut().insert(device.principal, device.last_counter);
+    });
+}
+
+// Devices registered for a location (only provider owner can see)
+#[ic_cdk::query]
+fn get_location_devices(provider_id: String, location_id: String) -> Result<Vec<Device>, SoulboardError> {
+    require_provider_location(&provider_id, &location_id, "view devices of")?;
+
+    DEVICE_REGISTRY.with(|registry| {
+        Ok(registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|device| device.provider_id == provider_id && device.location_id == location_id)
+            .collect())
+    })
+}
+
+// Checks a play event of a device against the bookings of its location and returns the booking
+// that covers it
+fn verify_play(device: &Device, event: &PlayEvent, now: u64) -> Result<String, String> {
+    if event.timestamp > now {
+        return Err("Play timestamp is in the future".to_string());
+    }
+    // Only active campaigns are billed, so plays of other campaigns are not accepted either
+    match CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&event.campaign_id)) {
+        Some(campaign) if campaign.status == CampaignStatus::Active => {}
+        Some(_) => return Err(format!("Campaign {} is not active", event.campaign_id)),
+        None => return Err(format!("Campaign {} not found", event.campaign_id)),
+    }
+    let location = location_key(&device.provider_id, &device.location_id);
+    match last_booking_starting_before(&location, event.timestamp + 1) {
+        Some(booking) if booking.end_time > event.timestamp && booking.campaign_id == event.campaign_id => {
+            Ok(booking.id)
+        }
+        _ => Err(format!("No booking of campaign {} covers this play", event.campaign_id)),
+    }
+}
+
+// Called by a registered device with plays it has shown. Every play has to carry a valid
+// attestation by the device and fall into a booking of the device's location by the played
+// campaign. Accepted plays count as views of the location and are appended to the play log;
+// rejected ones are returned with the reason.
+#[ic_cdk::update]
+fn report_plays(events: Vec<PlayEvent>) -> Result<PlayReportResult, SoulboardError> {
+    let mut device = DEVICE_REGISTRY.with(|registry| registry.borrow().get(&caller()))
+        .ok_or_else(|| SoulboardError::Unauthorized("Caller is not a registered device".to_string()))?;
+    if events.len() > MAX_PLAY_REPORT_SIZE {
+        return Err(SoulboardError::InvalidInput(format!("A report can hold at most {} plays", MAX_PLAY_REPORT_SIZE)));
+    }
+
+    let now = time();
+    let mut accepted = 0u64;
+    let mut rejected = Vec::new();
+    let mut played_slots: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    for (index, event) in events.into_iter().enumerate() {
+        if let Err(reason) = verify_play_attestation(&device, &event) {
+            device.invalid_attestations += 1;
+            rejected.push(RejectedPlay { index: index as u64, reason });
+            continue;
+        }
+        // The counter is used up by a valid attestation, even if the play is not accepted
+        device.last_counter = event.counter;
+
+        let booking_id = match verify_play(&device, &event, now) {
+            Ok(booking_id) => booking_id,
+            Err(reason) => {
+                rejected.push(RejectedPlay { index: index as u64, reason });
+                continue;
+            }
+        };
+        played_slots
+            .entry(booking_id.clone())
+            .or_default()
+            .insert(event.timestamp / SLOT_DURATION_NANOS * SLOT_DURATION_NANOS);
+        let record = PlayRecord {
+            device: device.principal,
+            provider_id: device.provider_id.clone(),
+            location_id: device.location_id.clone(),
+            booking_id,
+            campaign_id: event.campaign_id,
+            creative_hash: event.creative_hash,
+            timestamp: event.timestamp,
+            duration_ms: event.duration_ms,
+            reported_at: now,
+            counter: Some(event.counter),
+            signature: Some(event.signature),
+        };
+        PLAY_LOG.with(|log| log.borrow().append(&record))
+            .map_err(|e| SoulboardError::Internal(format!("Failed to store play: {:?}", e)))?;
+        accepted += 1;
+    }
+
+    DEVICE_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(device.principal, device.clone());
+    });
+
+    // Slots with a signed play can be attested by the provider
+    ESCROW_REGISTRY.with(|registry| {
+        let mut registry_borrow = registry.borrow_mut();
+        for (booking_id, slots) in played_slots {
+            if let Some(mut escrow) = registry_borrow.get(&booking_id) {
+                let mut played: BTreeSet<u64> = escrow.played_slots.unwrap_or_default().into_iter().collect();
+                played.extend(slots);
+                escrow.played_slots = Some(played.into_iter().collect());
+                registry_borrow.insert(booking_id, escrow);
+            }
+        }
+    });
+
+    if accepted > 0 {
+        PROVIDER_REGISTRY.with(|registry| {
+            let mut registry_borrow = registry.borrow_mut();
+            if let Some(mut provider) = registry_borrow.get(&device.provider_id) {
+                if let Some(location) = provider.locations.iter_mut().find(|location| location.id == device.location_id) {
+                    location.views += accepted;
+                }
+                registry_borrow.insert(device.provider_id.clone(), provider);
+            }
+        });
+    }
+
+    Ok(PlayReportResult { accepted, rejected })
+}
+
+// Entries of the play log from position `start` on, at most `limit` of them. The raw log covers
+// every provider and campaign, so it is restricted to controllers.
+#[ic_cdk::query]
+fn get_play_log(start: u64, limit: u64) -> Result<Vec<PlayRecord>, SoulboardError> {
+    if !is_controller(&caller()) {
+        return Err(SoulboardError::Unauthorized("Only controllers can read the play log".to_string()));
+    }
+
+    PLAY_LOG.with(|log| {
+        let log = log.borrow();
+        let end = log.len().min(start.saturating_add(limit));
+        Ok((start..end).filter_map(|index| log.get(index)).collect())
+    })
+}
+
+const BILLING_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Most play log entries one billing run processes
+const BILLING_BATCH_SIZE: u64 = 1_000;
+
+fn start_billing_timer() {
+    ic_cdk_timers::set_timer_interval(BILLING_INTERVAL, || {
+        run_billing_batch(BILLING_BATCH_SIZE);
+    });
+}
+
+// Bills the next plays of the play log and advances the billing cursor. The outcome depends only
+// on the play log, the stored billing state, the campaigns and the rates their bookings were made
+// at, never on the time of the run, so replaying the same log against the same state gives the
+// same charges.
+// Returns the number of plays processed.
+fn run_billing_batch(limit: u64) -> u64 {
+    let start = BILLING_STATE.with(|state| state.borrow().get().next_play);
+    let end = PLAY_LOG.with(|log| log.borrow().len()).min(start.saturating_add(limit));
+
+    for index in start..end {
+        if let Some(play) = PLAY_LOG.with(|log| log.borrow().get(index)) {
+            bill_play(&play);
+        }
+    }
+
+    BILLING_STATE.with(|state| {
+        state.borrow_mut().set(BillingState { next_play: end });
+    });
+    end - start
+}
+
+// Charges one impression of a play to its campaign, if its booking was made at a CPM-priced
+// location. The impression costs the rate recorded on the booking's escrow, so later pricing
+// changes of the location never reach bookings already made; a booking paid per slot is not
+// charged again per impression. A campaign whose budget cannot cover an impression is paused;
+// the impression is recorded as unpaid.
+fn bill_play(play: &PlayRecord) {
+    let Some(escrow) = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&play.booking_id)) else {
+        return;
+    };
+    let Some(cpm_rate) = booked_cpm_rate(&escrow, play) else {
+        return;
+    };
+    let Some(mut campaign) = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&play.campaign_id)) else {
+        return;
+    };
+    if campaign.token != escrow.token {
+        return;
+    }
+
+    let key = format!("{}:{}", play.campaign_id, location_key(&play.provider_id, &play.location_id));
+    let mut account = BILLING_ACCOUNTS.with(|accounts| accounts.borrow().get(&key)).unwrap_or_else(|| BillingAccount {
+        campaign_id: play.campaign_id.clone(),
+        provider_id: play.provider_id.clone(),
+        location_id: play.location_id.clone(),
+        token: escrow.token.clone(),
+        impressions: 0,
+        unpaid_impressions: 0,
+        charged: NumTokens::from(0u64),
+        carry: NumTokens::from(0u64),
+    });
+
+    let owed = account.carry.clone() + cpm_rate;
+    let charge = owed.clone() / 1000u64;
+    if campaign.budget < charge {
+        account.unpaid_impressions += 1;
+        if transition_campaign(&mut campaign, CampaignStatus::Paused).is_ok() {
+            CAMPAIGN_REGISTRY.with(|registry| {
+                registry.borrow_mut().insert(campaign.id.clone(), campaign);
+            });
+        }
+    } else {
+        account.impressions += 1;
+        account.carry = owed % 1000u64;
+        account.charged += charge.clone();
+        campaign.budget -= charge.clone();
+        // Pause as soon as the budget is used up rather than on the next impression
+        if campaign.budget == 0u64 && campaign.status == CampaignStatus::Active {
+            let _ = transition_campaign(&mut campaign, CampaignStatus::Paused);
+        }
+        CAMPAIGN_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert(campaign.id.clone(), campaign);
+        });
+        if charge > 0u64 {
+            credit_provider_earnings(&play.provider_id, &play.campaign_id, &account.token, charge.clone());
+            record_transaction(TransactionRecord {
+                memo: format!("impressions of booking {}", play.booking_id),
+                campaign_id: Some(play.campaign_id.clone()),
+                ..new_transaction(
+                    TransactionKind::Payment,
+                    TransactionAccount::Campaign(play.campaign_id.clone()),
+                    TransactionAccount::Provider(play.provider_id.clone()),
+                    charge,
+                    &account.token,
+                )
+            });
+        }
+    }
+
+    BILLING_ACCOUNTS.with(|accounts| {
+        accounts.borrow_mut().insert(key, account);
+    });
+}
+
+// CPM rate a play of the escrow's booking is billed at. Escrows made before the rate was recorded
+// fall back to the location's current rate, as long as the booking did not charge a slot fee.
+fn booked_cpm_rate(escrow: &BookingEscrow, play: &PlayRecord) -> Option<NumTokens> {
+    if escrow.cpm_rate.is_some() || escrow.slot_fee > 0u64 {
+        return escrow.cpm_rate.clone();
+    }
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&play.provider_id))?;
+    let location = provider.locations.iter().find(|location| location.id == play.location_id)?;
+    location.cpm_rate.clone().filter(|_| location.token == escrow.token)
+}
+
+// Index of the next play log entry the billing engine will process
+#[ic_cdk::query]
+fn get_billing_state() -> BillingState {
+    BILLING_STATE.with(|state| state.borrow().get().clone())
+}
+
+// Impressions and charges of a campaign per CPM-priced location (only campaign owner can see)
+#[ic_cdk::query]
+fn get_campaign_billing(campaign_id: String) -> Result<Vec<BillingAccount>, SoulboardError> {
+    require_campaign_owner(&campaign_id, "view")?;
+
+    let prefix = format!("{}:", campaign_id);
+    BILLING_ACCOUNTS.with(|accounts| {
+        Ok(accounts
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.value())
+            .collect())
+    })
+}
+
+// Checks that the provider exists and belongs to the caller
+fn require_provider_owner(provider_id: &str, action: &str) -> Result<Provider, SoulboardError> {
+    let caller_principal = caller();
+
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id.to_string()))
+        .ok_or_else(|| SoulboardError::not_found("provider", provider_id))?;
+    if provider.owner != caller_principal {
+        return Err(SoulboardError::Unauthorized(format!("You can only {} your own providers", action)));
+    }
+    Ok(provider)
+}
+
+// Stores a provider and indexes its locations. Locations removed from a provider have to be
+// taken out of the location index by the caller; the geo and search indexes are brought in line
+// with the new locations here.
+fn save_provider(provider: Provider) {
+    let previous = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider.id));
+    if previous.is_none() {
+        index_owner(&PROVIDER_OWNER_INDEX, PROVIDER_SEQUENCE, provider.owner, &provider.id);
+    }
+    reindex_search_documents(previous.as_ref(), Some(&provider));
+    GEO_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in previous.iter().flat_map(geo_index_keys) {
+            index.remove(&key);
+        }
+        for key in geo_index_keys(&provider) {
+            index.insert(key, ());
+        }
+    });
+    LOCATION_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for location in &provider.locations {
+            index.insert(PairKey(location.id.clone(), provider.id.clone()), ());
+        }
+    });
+    PROVIDER_REGISTRY.with(|registry| {
+        registry.borrow_mut().insert(provider.id.clone(), provider);
+    });
+}
+
+// Geo index entries of a provider's locations that have coordinates
+fn geo_index_keys(provider: &Provider) -> Vec<GeoKey> {
+    provider
+        .locations
+        .iter()
+        .filter_map(|location| {
+            let geohash = geohash_encode(location.coordinates.as_ref()?, GEOHASH_PRECISION);
+            Some(PairKey(geohash, PairKey(provider.id.clone(), location.id.clone())))
+        })
+        .collect()
+}
+
+// Builds a new location with a canister-generated ID
+fn new_location(input: LocationInput) -> Result<Location, SoulboardError> {
+    // Every location has to be priced in an allowed token
+    token_config(&input.token)?;
+    if let Some(coordinates) = &input.coordinates {
+        validate_coordinates(coordinates)?;
+    }
+
+    Ok(Location {
+        id: generate_location_id(),
+        name: input.name,
+        image: input.image,
+        base_fees: input.base_fees,
+        token: input.token,
+        views: 0,
+        status: LocationStatus::Active,
+        cpm_rate: input.cpm_rate,
+        coordinates: input.coordinates,
+        address: input.address,
+        venue_category: input.venue_category,
+        tags: input.tags,
+    })
+}
+
+// Renames one of the caller's providers (provider owner only)
+#[ic_cdk::update]
+fn update_provider(provider_id: String, name: String) -> Result<(), SoulboardError> {
+    let mut provider = require_provider_owner(&provider_id, "update")?;
+    provider.name = name;
+    save_provider(provider);
+    Ok(())
+}
+
+// Adds a location to one of the caller's providers and returns its ID (provider owner only)
+#[ic_cdk::update]
+fn add_location(provider_id: String, location: LocationInput) -> Result<String, SoulboardError> {
+    let mut provider = require_provider_owner(&provider_id, "add locations to")?;
+    let location = new_location(location)?;
+    let location_id = location.id.clone();
+    provider.locations.push(location);
+    save_provider(provider);
+    Ok(location_id)
+}
+
+// Updates the details and pricing of a location (provider owner only). Bookings already made keep
+// the slot fee or CPM rate they were booked at.
+#[ic_cdk::update]
+fn update_location(provider_id: String, location_id: String, update: LocationInput) -> Result<(), SoulboardError> {
+    let mut provider = require_provider_owner(&provider_id, "update locations of")?;
+    token_config(&update.token)?;
+    if let Some(coordinates) = &update.coordinates {
+        validate_coordinates(coordinates)?;
+    }
+
+    let location = provider.locations.iter_mut().find(|location| location.id == location_id)
+        .ok_or_else(|| SoulboardError::not_found("location", &location_id))?;
+    location.name = update.name;
+    location.image = update.image;
+    location.base_fees = update.base_fees;
+    location.token = update.token;
+    location.cpm_rate = update.cpm_rate;
+    location.coordinates = update.coordinates;
+    location.address = update.address;
+    location.venue_category = update.venue_category;
+    location.tags = update.tags;
+    save_provider(provider);
+    Ok(())
+}
+
+// Activates or deactivates a location (provider owner only). Inactive locations cannot be booked;
+// whether an active location is Booked is up to its bookings, so Booked cannot be set here.
+#[ic_cdk::update]
+fn set_location_status(provider_id: String, location_id: String, status: LocationStatus) -> Result<(), SoulboardError> {
+    if status == LocationStatus::Booked {
+        return Err(SoulboardError::InvalidInput("Booked is set by the canister while a booking is running".to_string()));
+    }
+    let mut provider = require_provider_owner(&provider_id, "update locations of")?;
+
+    let location = provider.locations.iter_mut().find(|location| location.id == location_id)
+        .ok_or_else(|| SoulboardError::not_found("location", &location_id))?;
+    location.status = status;
+    save_provider(provider);
+
+    // A reactivated location may be in the middle of a booking
+    refresh_location_status(&provider_id, &location_id);
+    Ok(())
+}
+
+// Removes a location without running or upcoming bookings (provider owner only). Its devices are
+// unregistered and it is unlinked from campaigns.
+#[ic_cdk::update]
+fn remove_location(provider_id: String, location_id: String) -> Result<(), SoulboardError> {
+    let mut provider = require_provider_owner(&provider_id, "remove locations of")?;
+    if !provider.locations.iter().any(|location| location.id == location_id) {
+        return Err(SoulboardError::not_found("location", &location_id));
+    }
+
+    // Bookings of a location never overlap, so the one starting last also ends last
+    let location = location_key(&provider_id, &location_id);
+    if let Some(booking) = last_booking_starting_before(&location, u64::MAX) {
+        if booking.end_time > time() {
+            return Err(SoulboardError::Conflict(format!("Location is booked until {} by {}", booking.end_time, booking.id)));
+        }
+    }
+
+    provider.locations.retain(|location| location.id != location_id);
+    save_provider(provider);
+    LOCATION_INDEX.with(|index| {
+        index.borrow_mut().remove(&PairKey(location_id.clone(), provider_id.clone()));
+    });
+
+    let devices: Vec<Device> = DEVICE_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|device| device.provider_id == provider_id && device.location_id == location_id)
+            .collect()
+    });
+    for device in &devices {
+        retire_device(device);
+    }
+
+    CAMPAIGN_PROVIDER_LINKS.with(|links| {
+        let mut links_borrow = links.borrow_mut();
+        let linked: Vec<(String, CampaignProviderLink)> = links_borrow
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value()))
+            .filter(|(_, link)| link.provider_id == provider_id && link.location_ids.contains(&location_id))
+            .collect();
+        for (key, mut link) in linked {
+            link.location_ids.retain(|id| id != &location_id);
+            // An empty list links every location of the provider, so a link narrowed to the
+            // removed location alone is deleted instead
+            if link.location_ids.is_empty() {
+                links_borrow.remove(&key);
+            } else {
+                links_borrow.insert(key, link);
+            }
+        }
+    });
+    Ok(())
+}
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 200;
+
+// Position and size of a page. The cursor is the `next_cursor` of the previous page; leave it
+// empty to start from the beginning.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct PageRequest {
+    cursor: Option<String>,
+    limit: Option<u64>, // DEFAULT_PAGE_SIZE when empty, at most MAX_PAGE_SIZE
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Page<T> {
+    items: Vec<T>,
+    next_cursor: Option<String>, // Set when there are more matches after this page
+    // Number of matches across all pages. Set by the marketplace and owner listings and by search
+    // unless its results are filtered by location fields; transaction pages leave it empty.
+    total: Option<u64>,
+}
+
+// Filters for location listings. Fee bounds are inclusive and apply to base_fees.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct LocationFilter {
+    provider_id: Option<String>,
+    status: Option<LocationStatus>,
+    token: Option<String>,
+    min_fee: Option<NumTokens>,
+    max_fee: Option<NumTokens>,
+}
+
+impl LocationFilter {
+    fn matches(&self, provider_id: &str, location: &Location) -> bool {
+        self.provider_id.as_ref().is_none_or(|id| id == provider_id)
+            && self.status.as_ref().is_none_or(|status| *status == location.status)
+            && self.token.as_ref().is_none_or(|token| *token == location.token)
+            && self.min_fee.as_ref().is_none_or(|min| location.base_fees >= *min)
+            && self.max_fee.as_ref().is_none_or(|max| location.base_fees <= *max)
+    }
+
+    // Whether any field other than provider_id is set, so that a location has to match
+    fn filters_locations(&self) -> bool {
+        self.status.is_some() || self.token.is_some() || self.min_fee.is_some() || self.max_fee.is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.provider_id.is_none() && !self.filters_locations()
+    }
+}
+
+// Fills a page from `matches`, which yields the matches after the page cursor in key order
+// together with their keys. Reading stops one match past the page, which only tells whether
+// another page follows.
+fn paginate<K: ToString, T>(mut matches: impl Iterator<Item = (K, T)>, limit: Option<u64>) -> Page<T> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut items = Vec::new();
+    let mut last_key = None;
+    for (key, item) in matches.by_ref().take(limit) {
+        last_key = Some(key);
+        items.push(item);
+    }
+    let next_cursor = matches.next().and(last_key).map(|key| key.to_string());
+    Page { items, next_cursor, total: None }
+}
+
+// Key range of the entries after a page cursor
+fn after_cursor<K>(cursor: Option<K>) -> (RangeBound<K>, RangeBound<K>) {
+    (cursor.map_or(RangeBound::Unbounded, RangeBound::Excluded), RangeBound::Unbounded)
+}
+
+// Cursor of a page over a registry with non-string keys
+fn page_cursor<K: std::str::FromStr>(page: &PageRequest) -> Result<Option<K>, SoulboardError> {
+    page.cursor
+        .as_ref()
+        .map(|cursor| cursor.parse::<K>())
+        .transpose()
+        .map_err(|_| SoulboardError::InvalidInput("Invalid page cursor".to_string()))
+}
+
+// Returns only campaigns created by the caller (PRIVATE)
+#[ic_cdk::query]
+fn get_my_campaigns(status: Option<CampaignStatus>, page: PageRequest) -> Page<Campaign> {
+    let caller_principal = caller();
+    
+    let campaign = |campaign_id: String| {
+        let campaign = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign_id))?;
+        status.as_ref().is_none_or(|status| *status == campaign.status).then_some((campaign_id, campaign))
+    };
+    CAMPAIGN_OWNER_INDEX.with(|index| {
+        let index = index.borrow();
+        // Campaigns change status, so only the count of all of the owner's campaigns is kept
+        let total = match status {
+            None => owner_count(caller_principal, CAMPAIGN_SEQUENCE),
+            Some(_) => owned_ids(&index, caller_principal, None).filter_map(campaign).count() as u64,
+        };
+        let matches = owned_ids(&index, caller_principal, page.cursor.clone()).filter_map(campaign);
+        Page { total: Some(total), ..paginate(matches, page.limit) }
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_providers(page: PageRequest) -> Page<Provider> {
+    let caller_principal = caller();
+    
+    PROVIDER_OWNER_INDEX.with(|index| {
+        let index = index.borrow();
+        let matches = owned_ids(&index, caller_principal, page.cursor.clone()).filter_map(|provider_id| {
+            let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))?;
+            Some((provider_id, provider))
+        });
+        let total = Some(owner_count(caller_principal, PROVIDER_SEQUENCE));
+        Page { total, ..paginate(matches, page.limit) }
+    })
+}
+
+// Providers matching the filter's provider_id. When the filter sets any location field, only
+// providers with at least one matching location are included.
+#[ic_cdk::query]
+fn get_all_providers(filter: LocationFilter, page: PageRequest) -> Page<Provider> {
+    let has_match = |provider: &Provider| {
+        filter.provider_id.as_ref().is_none_or(|id| *id == provider.id)
+            && (!filter.filters_locations()
+                || provider.locations.iter().any(|location| filter.matches(&provider.id, location)))
+    };
+    PROVIDER_REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        // Filters can match on any field, so a filtered total has to read every provider
+        let total = if filter.is_empty() {
+            registry.len()
+        } else {
+            registry.values().filter(|provider| has_match(provider)).count() as u64
+        };
+        let matches = registry.range(after_cursor(page.cursor.clone())).filter_map(|entry| {
+            let provider = entry.value();
+            has_match(&provider).then(|| (entry.key().clone(), provider))
+        });
+        Page { total: Some(total), ..paginate(matches, page.limit) }
+    })
+}
+
+// Locations in location ID order, read through the location index. Locations sharing a legacy
+// ID follow each other in provider ID order.
+#[ic_cdk::query]
+fn get_all_locations(filter: LocationFilter, page: PageRequest) -> Result<Page<LocationView>, SoulboardError> {
+    let cursor = page_cursor::<PairKey<String, String>>(&page)?;
+    let mut providers: BTreeMap<String, Option<Provider>> = BTreeMap::new();
+    LOCATION_INDEX.with(|index| {
+        let index = index.borrow();
+        let matches = index.range(after_cursor(cursor)).filter_map(|entry| {
+            let PairKey(location_id, provider_id) = entry.key().clone();
+            if filter.provider_id.as_ref().is_some_and(|id| *id != provider_id) {
+                return None;
+            }
+            let provider = providers.entry(provider_id.clone()).or_insert_with(|| {
+                PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+            }).as_ref()?;
+            let location = provider.locations.iter().find(|location| location.id == location_id)?;
+            filter.matches(&provider.id, location)
+                .then(|| (PairKey(location_id, provider_id), location_view(provider, location)))
+        });
+        let total = if filter.is_empty() { index.len() } else { matching_location_count(&filter) };
+        Ok(Page { total: Some(total), ..paginate(matches, page.limit) })
+    })
+}
+
+// Number of locations matching a filter, read provider by provider so every provider is decoded
+// only once
+fn matching_location_count(filter: &LocationFilter) -> u64 {
+    let count = |provider: Provider| {
+        provider.locations.iter().filter(|location| filter.matches(&provider.id, location)).count() as u64
+    };
+    PROVIDER_REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        match &filter.provider_id {
+            Some(provider_id) => registry.get(provider_id).map_or(0, count),
+            None => registry.values().map(count).sum(),
+        }
+    })
+}
+
+// A location together with the provider that owns it
+#[derive(CandidType, Deserialize, Clone)]
+struct LocationView {
+    provider_id: String,
+    provider_name: String,
+    location: Location,
+}
+
+fn location_view(provider: &Provider, location: &Location) -> LocationView {
+    LocationView {
+        provider_id: provider.id.clone(),
+        provider_name: provider.name.clone(),
+        location: location.clone(),
+    }
+}
+
+// Looks a location up through the location index. An ID shared by locations of several providers
+// resolves to the one whose provider ID comes first.
+fn find_location(location_id: &str) -> Result<(Provider, Location), SoulboardError> {
+    let provider_id = LOCATION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(PairKey(location_id.to_string(), String::new())..)
+            .next()
+            .map(|entry| entry.key().clone())
+            .filter(|key| key.0 == location_id)
+            .map(|key| key.1)
+    })
+    .ok_or_else(|| SoulboardError::not_found("location", location_id))?;
+    let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+        .ok_or_else(|| SoulboardError::not_found("provider", &provider_id))?;
+    let location = provider.locations.iter().find(|location| location.id == location_id).cloned()
+        .ok_or_else(|| SoulboardError::not_found("location", location_id))?;
+    Ok((provider, location))
+}
+
+#[ic_cdk::query]
+fn get_location(location_id: String) -> Result<LocationView, SoulboardError> {
+    let (provider, location) = find_location(&location_id)?;
+    Ok(location_view(&provider, &location))
+}
+
+#[ic_cdk::query]
+fn get_provider_by_location(location_id: String) -> Result<Provider, SoulboardError> {
+    find_location(&location_id).map(|(provider, _)| provider)
+}
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+// Precision of the geohashes in the geo index; a cell is about 5 m across
+const GEOHASH_PRECISION: usize = 9;
+// Most geohash cells scanned for one search area
+const MAX_GEO_CELLS: u64 = 32;
+const MAX_SEARCH_RADIUS_M: f64 = 1_000_000.0;
+const MAX_GEO_RESULTS: usize = 200;
+
+// A location found by a geographic search, with its distance from the search center
+#[derive(CandidType, Deserialize, Clone)]
+struct NearbyLocation {
+    distance_m: f64,
+    location: LocationView,
+}
+
+// An area between two parallels and two meridians that does not cross the antimeridian
+#[derive(Clone, Copy)]
+struct GeoBox {
+    south: f64,
+    west: f64,
+    north: f64,
+    east: f64,
+}
+
+impl GeoBox {
+    fn contains(&self, point: &Coordinates) -> bool {
+        (self.south..=self.north).contains(&point.latitude) && (self.west..=self.east).contains(&point.longitude)
+    }
+}
+
+fn validate_coordinates(point: &Coordinates) -> Result<(), SoulboardError> {
+    if !(-90.0..=90.0).contains(&point.latitude) {
+        return Err(SoulboardError::InvalidInput("Latitude must be between -90 and 90 degrees".to_string()));
+    }
+    if !(-180.0..=180.0).contains(&point.longitude) {
+        return Err(SoulboardError::InvalidInput("Longitude must be between -180 and 180 degrees".to_string()));
+    }
+    Ok(())
+}
+
+// Standard base-32 geohash: longitude and latitude bits interleaved, longitude first
+fn geohash_encode(point: &Coordinates, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even_bit = true;
+    for _ in 0..precision {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value): (&mut (f64, f64), f64) = if even_bit {
+                (&mut lon_range, point.longitude)
+            } else {
+                (&mut lat_range, point.latitude)
+            };
+            let mid = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= mid {
+                index |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even_bit = !even_bit;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    hash
+}
+
+// Height and width in degrees of a geohash cell of the given precision
+fn geohash_cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let lat_bits = bits / 2;
+    let lon_bits = bits - lat_bits;
+    (180.0 / 2f64.powi(lat_bits), 360.0 / 2f64.powi(lon_bits))
+}
+
+// Geohash cells covering an area, at the finest precision that needs at most MAX_GEO_CELLS
+fn geohash_cells(area: &GeoBox) -> Vec<String> {
+    let cell_range = |precision: usize| {
+        let (height, width) = geohash_cell_size(precision);
+        let lat_cells = (180.0 / height) as u64;
+        let lon_cells = (360.0 / width) as u64;
+        let lat_index = |lat: f64| (((lat + 90.0) / height) as u64).min(lat_cells - 1);
+        let lon_index = |lon: f64| (((lon + 180.0) / width) as u64).min(lon_cells - 1);
+        (lat_index(area.south), lat_index(area.north), lon_index(area.west), lon_index(area.east))
+    };
+
+    // Precision 1 splits the world into 32 cells, so some precision always fits
+    let precision = (1..=GEOHASH_PRECISION)
+        .rev()
+        .find(|&precision| {
+            let (south, north, west, east) = cell_range(precision);
+            (north - south + 1) * (east - west + 1) <= MAX_GEO_CELLS
+        })
+        .unwrap_or(1);
+
+    let (height, width) = geohash_cell_size(precision);
+    let (south, north, west, east) = cell_range(precision);
+    let mut cells = Vec::new();
+    for lat_index in south..=north {
+        for lon_index in west..=east {
+            let center = Coordinates {
+                latitude: -90.0 + (lat_index as f64 + 0.5) * height,
+                longitude: -180.0 + (lon_index as f64 + 0.5) * width,
+            };
+            cells.push(geohash_encode(&center, precision));
+        }
+    }
+    cells
+}
+
+// Great-circle distance in meters (haversine formula)
+fn distance_m(from: &Coordinates, to: &Coordinates) -> f64 {
+    let (lat1, lat2) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (to.longitude - from.longitude).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().min(1.0).asin()
+}
+
+// Splits an area at the antimeridian. `west` may be greater than `east` (the area crosses the
+// antimeridian) or outside -180..180 (as computed around a search center).
+fn split_at_antimeridian(south: f64, mut west: f64, north: f64, mut east: f64) -> Vec<GeoBox> {
+    if east - west >= 360.0 {
+        return vec![GeoBox { south, west: -180.0, north, east: 180.0 }];
+    }
+    if west < -180.0 {
+        west += 360.0;
+    }
+    if east > 180.0 {
+        east -= 360.0;
+    }
+    if west <= east {
+        vec![GeoBox { south, west, north, east }]
+    } else {
+        vec![
+            GeoBox { south, west, north, east: 180.0 },
+            GeoBox { south, west: -180.0, north, east },
+        ]
+    }
+}
+
+// Areas covering every point within `radius_m` of `center`
+fn radius_boxes(center: &Coordinates, radius_m: f64) -> Vec<GeoBox> {
+    let angle = radius_m / EARTH_RADIUS_M;
+    let dlat = angle.to_degrees();
+    let (south, north) = (center.latitude - dlat, center.latitude + dlat);
+    if south <= -90.0 || north >= 90.0 {
+        // The circle contains a pole, so it spans every meridian
+        return vec![GeoBox { south: south.max(-90.0), west: -180.0, north: north.min(90.0), east: 180.0 }];
+    }
+    let sin_dlon = angle.sin() / center.latitude.to_radians().cos();
+    let dlon = if sin_dlon >= 1.0 { 180.0 } else { sin_dlon.asin().to_degrees() };
+    split_at_antimeridian(south, center.longitude - dlon, north, center.longitude + dlon)
+}
+
+// Locations in the geo index within the given areas, nearest to `center` first
+fn locations_in_boxes(boxes: &[GeoBox], center: &Coordinates, max_distance_m: Option<f64>) -> Vec<NearbyLocation> {
+    // (provider ID, location ID) of every location in the cells
+    let mut candidates: BTreeSet<(String, String)> = BTreeSet::new();
+    GEO_INDEX.with(|index| {
+        let index = index.borrow();
+        for cell in boxes.iter().flat_map(geohash_cells) {
+            for entry in index
+                .range(PairKey(cell.clone(), PairKey(String::new(), String::new()))..)
+                .take_while(|entry| entry.key().0.starts_with(&cell))
+            {
+                let PairKey(provider_id, location_id) = entry.key().1.clone();
+                candidates.insert((provider_id, location_id));
+            }
+        }
+    });
+
+    let mut providers: BTreeMap<String, Option<Provider>> = BTreeMap::new();
+    let mut found: Vec<NearbyLocation> = candidates
+        .into_iter()
+        .filter_map(|(provider_id, location_id)| {
+            let provider = providers.entry(provider_id.clone()).or_insert_with(|| {
+                PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id))
+            }).as_ref()?;
+            let location = provider.locations.iter().find(|location| location.id == location_id)?;
+            let point = location.coordinates.as_ref()?;
+            let distance_m = distance_m(center, point);
+            let inside = match max_distance_m {
+                Some(radius_m) => distance_m <= radius_m,
+                None => boxes.iter().any(|area| area.contains(point)),
+            };
+            inside.then(|| NearbyLocation { distance_m, location: location_view(provider, location) })
+        })
+        .collect();
+    found.sort_by(|a, b| a.distance_m.total_cmp(&b.distance_m));
+    found.truncate(MAX_GEO_RESULTS);
+    found
+}
+
+// Locations within `radius_m` meters of a point, nearest first (at most MAX_GEO_RESULTS)
+#[ic_cdk::query]
+fn search_locations_near(latitude: f64, longitude: f64, radius_m: f64) -> Result<Vec<NearbyLocation>, SoulboardError> {
+    let center = Coordinates { latitude, longitude };
+    validate_coordinates(&center)?;
+    if !(radius_m > 0.0 && radius_m <= MAX_SEARCH_RADIUS_M) {
+        return Err(SoulboardError::InvalidInput(format!("Radius must be greater than 0 and at most {} meters", MAX_SEARCH_RADIUS_M)));
+    }
+
+    Ok(locations_in_boxes(&radius_boxes(&center, radius_m), &center, Some(radius_m)))
+}
+
+// Locations inside a bounding box, nearest to its center first (at most MAX_GEO_RESULTS). A box
+// whose west edge is east of its east edge crosses the antimeridian.
+#[ic_cdk::query]
+fn search_locations_in_box(south: f64, west: f64, north: f64, east: f64) -> Result<Vec<NearbyLocation>, SoulboardError> {
+    validate_coordinates(&Coordinates { latitude: south, longitude: west })?;
+    validate_coordinates(&Coordinates { latitude: north, longitude: east })?;
+    if south > north {
+        return Err(SoulboardError::InvalidInput("South edge must not be north of the north edge".to_string()));
+    }
+
+    let east_unwrapped = if west > east { east + 360.0 } else { east };
+    let mut center_longitude = (west + east_unwrapped) / 2.0;
+    if center_longitude > 180.0 {
+        center_longitude -= 360.0;
+    }
+    let center = Coordinates { latitude: (south + north) / 2.0, longitude: center_longitude };
+    Ok(locations_in_boxes(&split_at_antimeridian(south, west, north, east), &center, None))
+}
+
+// Most terms of a search query that are used
+const MAX_QUERY_TERMS: usize = 8;
+// Most index entries read for one query term. A short prefix can match most of the index, so
+// only the first entries in term order are scored.
+const MAX_SEARCH_CANDIDATES: usize = 1_000;
+// Terms are cut to this many characters, both when indexing and when searching
+const MAX_TERM_CHARS: usize = 32;
+// Weights of the fields a document's terms come from; an exact term match scores twice the
+// weight, a prefix match the weight itself
+const NAME_WEIGHT: u64 = 4;
+const CATEGORY_WEIGHT: u64 = 3;
+const TAG_WEIGHT: u64 = 3;
+const ADDRESS_WEIGHT: u64 = 2;
+const PROVIDER_NAME_WEIGHT: u64 = 1;
+
+impl VenueCategory {
+    fn label(&self) -> &'static str {
+        match self {
+            VenueCategory::Mall => "mall",
+            VenueCategory::Airport => "airport",
+            VenueCategory::TransitStation => "transit station",
+            VenueCategory::Street => "street",
+            VenueCategory::Stadium => "stadium",
+            VenueCategory::Restaurant => "restaurant",
+            VenueCategory::Retail => "retail",
+            VenueCategory::Office => "office",
+            VenueCategory::Other => "other",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+enum SearchKind {
+    Provider,
+    Location,
+}
+
+// Narrows search results. `kind` picks providers or locations only. The location filter's
+// provider applies to both kinds; the rest of it and `venue_category` apply to locations.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct SearchFilter {
+    kind: Option<SearchKind>,
+    venue_category: Option<VenueCategory>,
+    location: LocationFilter,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum SearchHit {
+    Provider(Provider),
+    Location(LocationView),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct SearchResult {
+    score: u64,
+    hit: SearchHit,
+}
+
+// Lowercased alphanumeric words of a text
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase().chars().take(MAX_TERM_CHARS).collect())
+        .collect()
+}
+
+fn add_terms(terms: &mut BTreeMap<String, u64>, text: &str, weight: u64) {
+    for term in tokenize(text) {
+        let entry = terms.entry(term).or_insert(0);
+        *entry = (*entry).max(weight);
+    }
+}
+
+fn provider_document(provider_id: &str) -> String {
+    format!("provider:{}", provider_id)
+}
+
+fn location_document(provider_id: &str, location_id: &str) -> String {
+    format!("location:{}", location_key(provider_id, location_id))
+}
+
+// Every searchable document of a provider (the provider and each of its locations) with its
+// weighted terms
+fn search_documents(provider: &Provider) -> Vec<(String, BTreeMap<String, u64>)> {
+    let mut provider_terms = BTreeMap::new();
+    add_terms(&mut provider_terms, &provider.name, NAME_WEIGHT);
+    let mut documents = vec![(provider_document(&provider.id), provider_terms)];
+
+    for location in &provider.locations {
+        let mut terms = BTreeMap::new();
+        add_terms(&mut terms, &location.name, NAME_WEIGHT);
+        if let Some(category) = &location.venue_category {
+            add_terms(&mut terms, category.label(), CATEGORY_WEIGHT);
+        }
+        for tag in location.tags.iter().flatten() {
+            add_terms(&mut terms, tag, TAG_WEIGHT);
+        }
+        if let Some(address) = &location.address {
+            add_terms(&mut terms, address, ADDRESS_WEIGHT);
+        }
+        add_terms(&mut terms, &provider.name, PROVIDER_NAME_WEIGHT);
+        documents.push((location_document(&provider.id, &location.id), terms));
+    }
+    documents
+}
+
+// Replaces the search index entries of a provider's previous documents with those of its current
+// ones. Either side may be missing, for a new or a removed provider.
+fn reindex_search_documents(previous: Option<&Provider>, current: Option<&Provider>) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (document, terms) in previous.map(search_documents).unwrap_or_default() {
+            for term in terms.into_keys() {
+                index.remove(&PairKey(term, document.clone()));
+            }
+        }
+        for (document, terms) in current.map(search_documents).unwrap_or_default() {
+            for (term, weight) in terms {
+                index.insert(PairKey(term, document.clone()), weight);
+            }
+        }
+    });
+}
+
+// Scores of the documents matching every query term. A term matches the indexed terms it is a
+// prefix of; each query term counts with its best match in the document.
+fn score_documents(query_terms: &[String]) -> BTreeMap<String, u64> {
+    let mut scores: Option<BTreeMap<String, u64>> = None;
+    SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        for query_term in query_terms {
+            let mut term_scores: BTreeMap<String, u64> = BTreeMap::new();
+            for entry in index
+                .range(PairKey(query_term.clone(), String::new())..)
+                .take_while(|entry| entry.key().0.starts_with(query_term.as_str()))
+                .take(MAX_SEARCH_CANDIDATES)
+            {
+                let PairKey(term, document) = entry.key();
+                let score = if term == query_term { entry.value() * 2 } else { entry.value() };
+                let best = term_scores.entry(document.clone()).or_insert(0);
+                *best = (*best).max(score);
+            }
+
+            scores = Some(match scores.take() {
+                None => term_scores,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(document, score)| {
+                        term_scores.get(&document).map(|term_score| (document, score + term_score))
+                    })
+                    .collect(),
+            });
+        }
+    });
+    scores.unwrap_or_default()
+}
+
+// Checks the parts of the filter a document ID answers (its kind and provider) without reading
+// the provider
+fn document_matches(document: &str, filter: &SearchFilter) -> bool {
+    let Some((kind, key)) = document.split_once(':') else {
+        return false;
+    };
+    let provider_id = key.split_once('/').map_or(key, |(provider_id, _)| provider_id);
+    let kind_matches = match kind {
+        "provider" => filter.kind.as_ref().is_none_or(|kind| *kind == SearchKind::Provider),
+        "location" => filter.kind.as_ref().is_none_or(|kind| *kind == SearchKind::Location),
+        _ => false,
+    };
+    kind_matches && filter.location.provider_id.as_ref().is_none_or(|id| id == provider_id)
+}
+
+// Resolves a matched document to a search hit, if it passes the location part of the filter.
+// Providers are read once per search through `providers`.
+fn search_hit(document: &str, filter: &SearchFilter, providers: &mut BTreeMap<String, Option<Provider>>) -> Option<SearchHit> {
+    let (kind, key) = document.split_once(':')?;
+    let (provider_id, location_id) = match key.split_once('/') {
+        Some((provider_id, location_id)) => (provider_id, Some(location_id)),
+        None => (key, None),
+    };
+    let provider = providers.entry(provider_id.to_string()).or_insert_with(|| {
+        PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id.to_string()))
+    }).as_ref()?;
+
+    match (kind, location_id) {
+        ("provider", None) => Some(SearchHit::Provider(provider.clone())),
+        ("location", Some(location_id)) => {
+            let location = provider.locations.iter().find(|location| location.id == location_id)?;
+            let category_matches = filter.venue_category.as_ref()
+                .is_none_or(|category| location.venue_category.as_ref() == Some(category));
+            (category_matches && filter.location.matches(&provider.id, location))
+                .then(|| SearchHit::Location(location_view(provider, location)))
+        }
+        _ => None,
+    }
+}
+
+// Full-text search over provider names and location names, venue categories, tags and addresses.
+// Campaigns are private to their owners and never appear in results. Every query word has to
+// match the start of a word in the result; results are ranked by score, best first. Documents
+// are ranked from the index alone, and only the ones a page returns are read. The cursor of a
+// search page is its position in the ranking. The total is the number of ranked documents, so it
+// is left empty when a location field or venue category filters them further.
+#[ic_cdk::query]
+fn search(query: String, filter: SearchFilter, page: PageRequest) -> Result<Page<SearchResult>, SoulboardError> {
+    let query_terms: Vec<String> = tokenize(&query).into_iter().take(MAX_QUERY_TERMS).collect();
+    if query_terms.is_empty() {
+        return Err(SoulboardError::InvalidInput("Search query must contain at least one word".to_string()));
+    }
+    let offset = match &page.cursor {
+        Some(cursor) => cursor.parse::<usize>().map_err(|_| SoulboardError::InvalidInput("Invalid search cursor".to_string()))?,
+        None => 0,
+    };
+
+    let mut ranked: Vec<(String, u64)> = score_documents(&query_terms)
+        .into_iter()
+        .filter(|(document, _)| document_matches(document, &filter))
+        .collect();
+    ranked.sort_by(|(a_document, a_score), (b_document, b_score)| {
+        b_score.cmp(a_score).then_with(|| a_document.cmp(b_document))
+    });
+
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut providers = BTreeMap::new();
+    let mut items = Vec::new();
+    let mut position = offset.min(ranked.len());
+    while position < ranked.len() && items.len() < limit {
+        let (document, score) = &ranked[position];
+        position += 1;
+        if let Some(hit) = search_hit(document, &filter, &mut providers) {
+            items.push(SearchResult { score: *score, hit });
+        }
+    }
+    let has_more = ranked[position..]
+        .iter()
+        .any(|(document, _)| search_hit(document, &filter, &mut providers).is_some());
+    let next_cursor = has_more.then(|| position.to_string());
+    let filters_documents = filter.location.filters_locations() || filter.venue_category.is_some();
+    let total = (!filters_documents).then_some(ranked.len() as u64);
+    Ok(Page { items, next_cursor, total })
+}
+
+// Get providers for a specific campaign (only if caller owns the campaign)
+#[ic_cdk::query]
+fn get_providers_for_campaign(campaign_id: String) -> Result<Vec<Provider>, SoulboardError> {
+    require_campaign_owner(&campaign_id, "view")?;
+
+    // Providers removed since they were linked are skipped
+    PROVIDER_REGISTRY.with(|registry| {
+        let registry_borrow = registry.borrow();
+        Ok(campaign_provider_links(&campaign_id)
+            .into_iter()
+            .filter_map(|link| registry_borrow.get(&link.provider_id))
+            .collect())
+    })
+}
+
+// Providers and locations linked to a campaign (only if caller owns the campaign)
+#[ic_cdk::query]
+fn get_campaign_provider_links(campaign_id: String) -> Result<Vec<CampaignProviderLink>, SoulboardError> {
+    require_campaign_owner(&campaign_id, "view")?;
+    Ok(campaign_provider_links(&campaign_id))
+}
+
+ic_cdk::export_candid!();
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::task::{Context, Poll, Waker};
+
+    // Every test runs on its own thread, so each one starts from empty stable memory
+    thread_local! {
+        static CLOCK: Cell<u64> = const { Cell::new(1_700_000_000_000_000_000) };
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static LEDGER: RefCell<MockLedger> = RefCell::new(MockLedger::default());
+    }
+
+    #[derive(Clone, Copy, Default, PartialEq)]
+    enum LedgerOutcome {
+        #[default]
+        Execute,
+        Reject, // The ledger answers with an error; no funds move
+        Unknown, // The call fails without telling whether the ledger executed it
+    }
+
+    // Ledger standing in for every token's ledger. While `held`, calls wait for an answer, so a
+    // test can run other calls while one is awaiting the ledger.
+    #[derive(Default)]
+    struct MockLedger {
+        held: bool,
+        outcome: LedgerOutcome,
+        executed: Vec<(Account, NumTokens)>,
+    }
+
+    impl MockLedger {
+        fn answer(&mut self, to: Account, amount: NumTokens) -> Result<BlockIndex, TransferFailure> {
+            match self.outcome {
+                LedgerOutcome::Execute => {
+                    self.executed.push((to, amount));
+                    Ok(BlockIndex::from(self.executed.len() as u64))
+                }
+                LedgerOutcome::Reject => Err(TransferFailure::Definite(SoulboardError::LedgerError(
+                    TransferError::TemporarilyUnavailable,
+                ))),
+                LedgerOutcome::Unknown => Err(call_failure(RejectionCode::SysTransient, "timeout".to_string())),
+            }
+        }
+    }
+
+    // Resolves once the mocked ledger is no longer held
+    struct LedgerAnswer;
+
+    impl Future for LedgerAnswer {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            if LEDGER.with(|ledger| ledger.borrow().held) { Poll::Pending } else { Poll::Ready(()) }
+        }
+    }
+
+    pub(super) async fn ledger_transfer(
+        _token: &TokenConfig,
+        _from_subaccount: Option<Subaccount>,
+        to: Account,
+        _memo: Option<Vec<u8>>,
+        amount: NumTokens,
+        _created_at_time: u64,
+    ) -> Result<BlockIndex, TransferFailure> {
+        LedgerAnswer.await;
+        LEDGER.with(|ledger| ledger.borrow_mut().answer(to, amount))
+    }
+
+    pub(super) async fn ledger_transfer_from(
+        _token: &TokenConfig,
+        _from: Account,
+        to: Account,
+        _memo: Option<Vec<u8>>,
+        amount: NumTokens,
+        _created_at_time: u64,
+    ) -> Result<BlockIndex, TransferFailure> {
+        LedgerAnswer.await;
+        LEDGER.with(|ledger| ledger.borrow_mut().answer(to, amount))
+    }
+
+    fn hold_ledger(held: bool) {
+        LEDGER.with(|ledger| ledger.borrow_mut().held = held);
+    }
+
+    fn set_ledger_outcome(outcome: LedgerOutcome) {
+        LEDGER.with(|ledger| ledger.borrow_mut().outcome = outcome);
+    }
+
+    fn executed_transfers() -> Vec<(Account, NumTokens)> {
+        LEDGER.with(|ledger| ledger.borrow().executed.clone())
+    }
+
+    fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn run<F: Future>(future: F) -> F::Output {
+        match poll_once(pin!(future)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future is still awaiting the ledger"),
+        }
+    }
+
+    pub(super) fn time() -> u64 {
+        CLOCK.with(|clock| clock.get())
+    }
+
+    pub(super) fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
+
+    // user(99) stands in for the canister's controllers
+    pub(super) fn is_controller(principal: &Principal) -> bool {
+        *principal == user(99)
+    }
+
+    fn set_time(nanos: u64) {
+        CLOCK.with(|clock| clock.set(nanos));
+    }
+
+    fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    // Registers the default ICP token, as init does
+    fn setup() {
+        set_config(CanisterConfig::default());
+    }
+
+    fn location_input(name: &str) -> LocationInput {
+        LocationInput {
+            name: name.to_string(),
+            image: format!("https://images.example.com/{}.png", name),
+            base_fees: NumTokens::from(100_000u64),
+            token: "ICP".to_string(),
+            cpm_rate: None,
+            coordinates: None,
+            address: None,
+            venue_category: None,
+            tags: None,
+        }
+    }
+
+    fn test_campaign(id: &str, owner: Principal) -> Campaign {
+        Campaign {
+            id: id.to_string(),
+            name: format!("Campaign {}", id),
+            description: String::new(),
+            image: None,
+            locations: None,
+            budget: NumTokens::from(0u64),
+            token: "ICP".to_string(),
+            owner,
+            status: CampaignStatus::Draft,
+            start_time: None,
+            end_time: None,
+            ended_at: None,
+            refund_block_index: None,
+        }
+    }
+
+    // What post_upgrade does, with the migration and rebuild passes run to the end instead of on
+    // timers
+    fn upgrade() {
+        plan_rebuilds();
+        MIGRATION_CURSOR.with(|cursor| *cursor.borrow_mut() = (0, None));
+        while migrate_next_batch() {}
+    }
+
+    #[test]
+    fn max_id_suffix_ignores_foreign_keys() {
+        let keys = ["campaign_3", "campaign_12", "campaign_x", "campaigns_40", "lobby", "campaign_"];
+        assert_eq!(max_id_suffix(keys.iter().map(|key| key.to_string()), CAMPAIGN_SEQUENCE), 12);
+        assert_eq!(max_id_suffix(std::iter::empty(), CAMPAIGN_SEQUENCE), 0);
+    }
+
+    // A canister deployed while the counters lived on the heap comes back from its upgrade with
+    // records stored and an empty sequence cell
+    #[test]
+    fn upgrade_from_heap_counters_never_reuses_ids() {
+        setup();
+        let owner = user(1);
+        for id in ["campaign_1", "campaign_2", "campaign_7"] {
+            CAMPAIGN_REGISTRY.with(|registry| registry.borrow_mut().insert(id.to_string(), test_campaign(id, owner)));
+        }
+        let mut lobby = new_location(location_input("lobby")).unwrap();
+        lobby.id = "location_5".to_string();
+        let mut legacy = new_location(location_input("legacy")).unwrap();
+        legacy.id = "front-window".to_string();
+        PROVIDER_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert("provider_3".to_string(), Provider {
+                id: "provider_3".to_string(),
+                name: "Screens".to_string(),
+                owner,
+                locations: vec![lobby, legacy],
+                total_earnings: BTreeMap::new(),
+            })
+        });
+        ID_SEQUENCES.with(|cell| cell.borrow_mut().set(IdSequences::default()));
+
+        upgrade();
+
+        assert_eq!(generate_campaign_id(), "campaign_8");
+        assert_eq!(generate_provider_id(), "provider_4");
+        assert_eq!(generate_location_id(), "location_6");
+    }
+
+    // IDs handed out before an upgrade stay taken after it
+    #[test]
+    fn ids_continue_across_upgrades() {
+        setup();
+        set_caller(user(1));
+        let first = create_campaign("Spring".to_string(), String::new(), None, None, None).unwrap();
+        let provider = register_provider("Screens".to_string(), vec![location_input("lobby")]).unwrap();
+
+        upgrade();
+
+        let second = create_campaign("Summer".to_string(), String::new(), None, None, None).unwrap();
+        let next_provider = register_provider("More screens".to_string(), vec![location_input("hall")]).unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("campaign_1", "campaign_2"));
+        assert_eq!((provider.as_str(), next_provider.as_str()), ("provider_1", "provider_2"));
+        let location_ids: Vec<String> = PROVIDER_REGISTRY
+            .with(|registry| registry.borrow().iter().flat_map(|entry| entry.value().locations).map(|l| l.id).collect());
+        assert_eq!(location_ids, vec!["location_1", "location_2"]);
+    }
+
+    // A sequence that fell behind the registry skips the taken IDs instead of overwriting them
+    #[test]
+    fn generated_ids_skip_taken_keys() {
+        setup();
+        CAMPAIGN_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert("campaign_1".to_string(), test_campaign("campaign_1", user(1)))
+        });
+        assert_eq!(generate_campaign_id(), "campaign_2");
+    }
+
+    #[test]
+    fn provider_with_dozens_of_locations_is_stored() {
+        setup();
+        set_caller(user(1));
+        let locations: Vec<LocationInput> = (0..48)
+            .map(|n| LocationInput {
+                address: Some(format!("{} Long Street, Unit {}, Some City, Some Country", n, n * 7)),
+                venue_category: Some(VenueCategory::Mall),
+                tags: Some(vec!["indoor".to_string(), "digital".to_string(), format!("zone-{}", n)]),
+                coordinates: Some(Coordinates { latitude: 48.0 + n as f64 / 100.0, longitude: 2.0 }),
+                ..location_input(&format!("screen-{}-with-a-rather-long-descriptive-name", n))
+            })
+            .collect();
+        let provider_id = register_provider("Screens".to_string(), locations).unwrap();
+
+        let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id)).unwrap();
+        assert_eq!(provider.locations.len(), 48);
+        assert_eq!(provider.locations[47].name, "screen-47-with-a-rather-long-descriptive-name");
+        assert_eq!(provider.locations[47].tags.as_ref().unwrap()[2], "zone-47");
+        assert!(provider.to_bytes().len() > 10_000);
+    }
+
+    #[test]
+    fn campaign_with_long_description_is_stored() {
+        setup();
+        set_caller(user(1));
+        let description = "Spring sale across all stores. ".repeat(4_000);
+        let campaign_id = create_campaign(
+            "Spring".to_string(),
+            description.clone(),
+            Some("https://images.example.com/spring.png".to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let campaign = CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign_id)).unwrap();
+        assert_eq!(campaign.description, description);
+        assert_eq!(campaign.owner, user(1));
+    }
+
+    // Records written as bare Candid while the types were still bounded decode as version 0
+    #[test]
+    fn bare_records_from_bounded_layout_decode() {
+        setup();
+        let legacy = ProviderV1 {
+            id: "provider_1".to_string(),
+            name: "Screens".to_string(),
+            owner: user(1),
+            locations: vec![LocationV1 {
+                id: "lobby".to_string(),
+                name: "Lobby".to_string(),
+                image: String::new(),
+                base_fees: NumTokens::from(5u64),
+                views: 3,
+                status: LocationStatus::Active,
+            }],
+            total_earnings: NumTokens::from(7u64),
+        };
+        let provider = Provider::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+        assert_eq!(provider.locations[0].token, "ICP");
+        assert_eq!(provider.total_earnings.get("ICP"), Some(&NumTokens::from(7u64)));
+
+        let earnings = ProviderEarningsV1 {
+            provider_id: "provider_1".to_string(),
+            campaign_id: "campaign_1".to_string(),
+            total_earned: NumTokens::from(9u64),
+            last_withdrawal: None,
+        };
+        let earnings = ProviderEarnings::from_bytes(Cow::Owned(Encode!(&earnings).unwrap()));
+        assert_eq!((earnings.token.as_str(), earnings.total_earned), ("ICP", NumTokens::from(9u64)));
+    }
+
+    // Optional fields added without a version bump decode as None from records written before them
+    #[test]
+    fn optional_fields_need_no_version_bump() {
+        #[derive(CandidType)]
+        struct CampaignBeforeScheduling {
+            id: String,
+            name: String,
+            description: String,
+            image: Option<String>,
+            locations: Option<Vec<Location>>,
+            budget: NumTokens,
+            token: String,
+            owner: Principal,
+            status: CampaignStatus,
+        }
+        let record = VersionedRecord {
+            version: Campaign::SCHEMA_VERSION,
+            payload: Encode!(&CampaignBeforeScheduling {
+                id: "campaign_1".to_string(),
+                name: "Spring".to_string(),
+                description: String::new(),
+                image: None,
+                locations: None,
+                budget: NumTokens::from(10u64),
+                token: "ICP".to_string(),
+                owner: user(1),
+                status: CampaignStatus::Active,
+            })
+            .unwrap(),
+        };
+        let campaign: Campaign = decode_versioned(&Encode!(&record).unwrap()).unwrap();
+        assert_eq!(campaign.budget, NumTokens::from(10u64));
+        assert!(campaign.start_time.is_none() && campaign.refund_block_index.is_none());
+    }
+
+    #[test]
+    fn unknown_versions_are_reported() {
+        let record = VersionedRecord { version: 0, payload: vec![1, 2, 3] };
+        let error = decode_versioned::<Receipt>(&Encode!(&record).unwrap()).err().unwrap();
+        assert_eq!(error, "unsupported Receipt schema version 0");
+
+        let newer = VersionedRecord { version: Receipt::SCHEMA_VERSION + 1, payload: Vec::new() };
+        assert!(decode_versioned::<Receipt>(&Encode!(&newer).unwrap()).is_err());
+    }
+
+    fn tokens(amount: u64) -> NumTokens {
+        NumTokens::from(amount)
+    }
+
+    // A provider of user(1) holding `earnings` ICP
+    fn provider_with_earnings(earnings: u64) -> String {
+        set_caller(user(1));
+        let provider_id = register_provider("Screens".to_string(), vec![location_input("lobby")]).unwrap();
+        PROVIDER_REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            let mut provider = registry.get(&provider_id).unwrap();
+            provider.total_earnings.insert("ICP".to_string(), tokens(earnings));
+            registry.insert(provider_id.clone(), provider);
+        });
+        provider_id
+    }
+
+    fn provider_earnings(provider_id: &str) -> NumTokens {
+        PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id.to_string()))
+            .and_then(|provider| provider.total_earnings.get("ICP").cloned())
+            .unwrap_or_default()
+    }
+
+    fn campaign_with_budget(budget: u64) -> String {
+        set_caller(user(1));
+        let campaign_id = create_campaign("Spring".to_string(), String::new(), None, None, None).unwrap();
+        CAMPAIGN_REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            let mut campaign = registry.get(&campaign_id).unwrap();
+            campaign.budget = tokens(budget);
+            registry.insert(campaign_id.clone(), campaign);
+        });
+        campaign_id
+    }
+
+    fn campaign_budget(campaign_id: &str) -> NumTokens {
+        CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign_id.to_string())).unwrap().budget
+    }
+
+    // The default ICP fee is 10_000
+    #[test]
+    fn concurrent_provider_withdrawals_pay_once() {
+        setup();
+        let provider_id = provider_with_earnings(1_000_000);
+
+        hold_ledger(true);
+        let mut first = pin!(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(900_000)));
+        assert!(poll_once(first.as_mut()).is_pending());
+        assert_eq!(provider_earnings(&provider_id), tokens(90_000));
+
+        let second = run(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(900_000)));
+        assert!(matches!(second, Err(SoulboardError::Conflict(_))));
+
+        hold_ledger(false);
+        let Poll::Ready(receipt) = poll_once(first.as_mut()) else { panic!("withdrawal did not finish") };
+        let receipt = receipt.unwrap();
+        assert_eq!((receipt.amount, receipt.fee, receipt.balance), (tokens(900_000), tokens(10_000), tokens(90_000)));
+        assert_eq!(executed_transfers(), vec![(principal_to_account(user(1)), tokens(900_000))]);
+
+        // The guard is released, and the balance no longer covers another withdrawal
+        let third = run(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(900_000)));
+        assert!(matches!(third, Err(SoulboardError::InsufficientFunds { .. })));
+    }
+
+    #[test]
+    fn rejected_withdrawal_restores_amount_and_fee() {
+        setup();
+        let provider_id = provider_with_earnings(1_000_000);
+        set_ledger_outcome(LedgerOutcome::Reject);
+
+        let result = run(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(500_000)));
+        assert!(matches!(result, Err(SoulboardError::LedgerError(_))));
+        assert_eq!(provider_earnings(&provider_id), tokens(1_000_000));
+        assert!(PENDING_TRANSFERS.with(|journal| journal.borrow().is_empty()));
+        assert!(executed_transfers().is_empty());
+    }
+
+    // After an unknown outcome the funds stay debited until a retry settles the transfer
+    #[test]
+    fn uncertain_withdrawal_stays_debited_until_retried() {
+        setup();
+        let provider_id = provider_with_earnings(1_000_000);
+        set_ledger_outcome(LedgerOutcome::Unknown);
+
+        let result = run(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(500_000)));
+        let Err(SoulboardError::TransferPending { transfer_id, .. }) = result else { panic!("transfer should be pending") };
+        assert_eq!(provider_earnings(&provider_id), tokens(490_000));
+        let pending = PENDING_TRANSFERS.with(|journal| journal.borrow().get(&transfer_id)).unwrap();
+        assert!(pending.status == PendingTransferStatus::Uncertain);
+
+        // A concurrent withdrawal cannot spend the held funds while the transfer is uncertain
+        let retry_blocked = run(withdraw_provider_earnings(provider_id.clone(), "ICP".to_string(), tokens(500_000)));
+        assert!(matches!(retry_blocked, Err(SoulboardError::InsufficientFunds { .. })));
+
+        set_ledger_outcome(LedgerOutcome::Execute);
+        assert!(run(execute_journaled_transfer(transfer_id)).is_ok());
+        assert_eq!(provider_earnings(&provider_id), tokens(490_000));
+        assert!(stored_receipt(transfer_id).is_ok());
+        assert_eq!(executed_transfers().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_campaign_withdrawals_are_rejected() {
+        setup();
+        let campaign_id = campaign_with_budget(300_000);
+
+        hold_ledger(true);
+        let mut first = pin!(withdraw_campaign_funds(campaign_id.clone(), tokens(200_000)));
+        assert!(poll_once(first.as_mut()).is_pending());
+        let second = run(withdraw_campaign_funds(campaign_id.clone(), tokens(50_000)));
+        assert!(matches!(second, Err(SoulboardError::Conflict(_))));
+        // A close would also refund the budget the first withdrawal is holding
+        let close = run(close_campaign(campaign_id.clone()));
+        assert!(matches!(close, Err(SoulboardError::Conflict(_))));
+
+        hold_ledger(false);
+        assert!(matches!(poll_once(first.as_mut()), Poll::Ready(Ok(_))));
+        assert_eq!(campaign_budget(&campaign_id), tokens(90_000));
+    }
+
+    #[test]
+    fn zero_withdrawals_are_rejected() {
+        setup();
+        let provider_id = provider_with_earnings(1_000_000);
+        let campaign_id = campaign_with_budget(1_000_000);
+        let provider = run(withdraw_provider_earnings(provider_id, "ICP".to_string(), tokens(0)));
+        let campaign = run(withdraw_campaign_funds(campaign_id, tokens(0)));
+        assert!(matches!(provider, Err(SoulboardError::InvalidInput(_))));
+        assert!(matches!(campaign, Err(SoulboardError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn pair_keys_round_trip_and_order_like_tuples() {
+        let keys = [PairKey("b".to_string(), 1u64), PairKey("a".to_string(), 9), PairKey("ab".to_string(), 0)];
+        for key in &keys {
+            assert!(PairKey::<String, u64>::from_bytes(key.to_bytes()) == *key);
+        }
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        let order: Vec<(&str, u64)> = sorted.iter().map(|key| (key.0.as_str(), key.1)).collect();
+        assert_eq!(order, vec![("a", 9), ("ab", 0), ("b", 1)]);
+    }
+
+    fn next_slot() -> u64 {
+        (time() / SLOT_DURATION_NANOS + 1) * SLOT_DURATION_NANOS
+    }
+
+    // A campaign of user(1) linked to a location of user(2), priced per slot or per impression
+    fn booking_fixture(cpm_rate: Option<u64>) -> (String, String, String) {
+        setup();
+        set_caller(user(2));
+        let provider_id = register_provider(
+            "Screens".to_string(),
+            vec![LocationInput { cpm_rate: cpm_rate.map(tokens), ..location_input("lobby") }],
+        )
+        .unwrap();
+        let location_id = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id)).unwrap().locations[0].id.clone();
+        let campaign_id = campaign_with_budget(10_000_000);
+        add_provider(campaign_id.clone(), provider_id.clone(), Vec::new()).unwrap();
+        (campaign_id, provider_id, location_id)
+    }
+
+    fn booking(booking_id: &str) -> Booking {
+        BOOKING_REGISTRY.with(|registry| registry.borrow().get(&booking_id.to_string())).unwrap()
+    }
+
+    #[test]
+    fn paid_bookings_hold_their_range() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        let start = next_slot();
+        let booking_id = book_location(campaign_id.clone(), provider_id.clone(), location_id.clone(), start, start + 3 * SLOT_DURATION_NANOS).unwrap();
+
+        assert!(booking(&booking_id).status == BookingStatus::Confirmed);
+        assert_eq!(campaign_budget(&campaign_id), tokens(10_000_000 - 3 * 100_000));
+        let overlap = book_location(campaign_id, provider_id, location_id, start + 2 * SLOT_DURATION_NANOS, start + 4 * SLOT_DURATION_NANOS);
+        assert!(matches!(overlap, Err(SoulboardError::Conflict(_))));
+    }
+
+    #[test]
+    fn bookings_longer_than_the_cap_are_rejected() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        let start = next_slot();
+        let result = book_location(campaign_id, provider_id, location_id, start, start + MAX_BOOKING_NANOS + SLOT_DURATION_NANOS);
+        assert!(matches!(result, Err(SoulboardError::InvalidInput(_))));
+    }
+
+    // A CPM booking escrows nothing, so it only holds the location once the provider accepts it
+    #[test]
+    fn free_bookings_wait_for_provider_acceptance() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(Some(2_000));
+        let start = next_slot();
+        let end = start + 2 * SLOT_DURATION_NANOS;
+        let first = book_location(campaign_id.clone(), provider_id.clone(), location_id.clone(), start, end).unwrap();
+        let second = book_location(campaign_id.clone(), provider_id.clone(), location_id.clone(), start, end).unwrap();
+        assert!(booking(&first).status == BookingStatus::Requested);
+        assert!(!is_booked_at(&location_key(&provider_id, &location_id), start));
+
+        // Only the provider owner can accept
+        assert!(matches!(accept_booking(first.clone()), Err(SoulboardError::Unauthorized(_))));
+        set_caller(user(2));
+        assert_eq!(get_booking_requests(provider_id.clone()).unwrap().len(), 2);
+        accept_booking(first.clone()).unwrap();
+        assert!(is_booked_at(&location_key(&provider_id, &location_id), start));
+        assert!(matches!(accept_booking(second.clone()), Err(SoulboardError::Conflict(_))));
+
+        // Declining the overlapping request leaves the accepted booking's range in place
+        decline_booking(second.clone()).unwrap();
+        assert!(booking(&second).status == BookingStatus::Cancelled);
+        assert!(is_booked_at(&location_key(&provider_id, &location_id), start));
+    }
+
+    #[test]
+    fn expired_booking_requests_cannot_be_accepted() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(Some(2_000));
+        let start = next_slot();
+        let request = book_location(campaign_id, provider_id, location_id, start, start + SLOT_DURATION_NANOS).unwrap();
+        set_time(start + 1);
+        set_caller(user(2));
+        assert!(matches!(accept_booking(request), Err(SoulboardError::Conflict(_))));
+    }
+
+    fn activate(campaign_id: &str) {
+        CAMPAIGN_REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            let mut campaign = registry.get(&campaign_id.to_string()).unwrap();
+            campaign.status = CampaignStatus::Active;
+            registry.insert(campaign_id.to_string(), campaign);
+        });
+    }
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn signed_play(key: &SigningKey, device: &Principal, campaign_id: &str, timestamp: u64, counter: u64) -> PlayEvent {
+        let mut event = PlayEvent {
+            campaign_id: campaign_id.to_string(),
+            creative_hash: "sha256:creative".to_string(),
+            timestamp,
+            duration_ms: 15_000,
+            counter,
+            signature: Vec::new(),
+        };
+        event.signature = key.sign(&play_signing_message(device, &event)).to_bytes().to_vec();
+        event
+    }
+
+    // Books the two slots after the current one and registers device user(9) at the location
+    fn booked_location_with_device(cpm_rate: Option<u64>) -> (String, String, String, u64) {
+        let (campaign_id, provider_id, location_id) = booking_fixture(cpm_rate);
+        let start = next_slot();
+        let booking_id = book_location(campaign_id.clone(), provider_id.clone(), location_id.clone(), start, start + 2 * SLOT_DURATION_NANOS).unwrap();
+        activate(&campaign_id);
+        set_caller(user(2));
+        if cpm_rate.is_some() {
+            accept_booking(booking_id.clone()).unwrap();
+        }
+        register_device(provider_id.clone(), location_id, user(9), signing_key(9).verifying_key().to_bytes().to_vec()).unwrap();
+        (campaign_id, provider_id, booking_id, start)
+    }
+
+    #[test]
+    fn providers_attest_only_slots_with_signed_plays() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(None);
+        let second_slot = start + SLOT_DURATION_NANOS;
+
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let play = signed_play(&signing_key(9), &user(9), &campaign_id, start + 300_000_000_000, 1);
+        assert_eq!(report_plays(vec![play]).unwrap().accepted, 1);
+
+        set_time(start + 2 * SLOT_DURATION_NANOS);
+        set_caller(user(2));
+        assert!(matches!(attest_delivery(booking_id.clone(), vec![second_slot]), Err(SoulboardError::Conflict(_))));
+        assert_eq!(attest_delivery(booking_id.clone(), vec![start]).unwrap(), tokens(100_000));
+
+        // Only the two sides of the booking can attest
+        set_caller(user(7));
+        assert!(matches!(attest_delivery(booking_id.clone(), vec![second_slot]), Err(SoulboardError::Unauthorized(_))));
+
+        // The advertiser can confirm a slot no device reported
+        set_caller(user(1));
+        assert_eq!(attest_delivery(booking_id.clone(), vec![second_slot]).unwrap(), tokens(100_000));
+        assert_eq!(provider_earnings(&provider_id), tokens(200_000));
+    }
+
+    fn test_device(key: &SigningKey, last_counter: u64) -> Device {
+        Device {
+            principal: user(9),
+            provider_id: "provider_1".to_string(),
+            location_id: "location_1".to_string(),
+            registered_at: 0,
+            public_key: Some(key.verifying_key().to_bytes().to_vec()),
+            last_counter,
+            invalid_attestations: 0,
+        }
+    }
+
+    // Attestations are checked in pure Rust, without a canister or a network
+    #[test]
+    fn play_attestations_verify_offline() {
+        let key = signing_key(9);
+        let device = test_device(&key, 4);
+        let play = signed_play(&key, &user(9), "campaign_1", 1_000, 5);
+        assert_eq!(verify_play_attestation(&device, &play), Ok(()));
+
+        let mut tampered = play.clone();
+        tampered.duration_ms += 1;
+        assert!(verify_play_attestation(&device, &tampered).is_err());
+
+        let other_device = signed_play(&key, &user(8), "campaign_1", 1_000, 5);
+        assert!(verify_play_attestation(&device, &other_device).is_err());
+
+        let wrong_key = signed_play(&signing_key(3), &user(9), "campaign_1", 1_000, 5);
+        assert!(verify_play_attestation(&device, &wrong_key).is_err());
+
+        let replayed = signed_play(&key, &user(9), "campaign_1", 1_000, 4);
+        assert!(verify_play_attestation(&device, &replayed).unwrap_err().contains("counter"));
+
+        let unkeyed = Device { public_key: None, ..test_device(&key, 0) };
+        assert!(verify_play_attestation(&unkeyed, &play).is_err());
+    }
+
+    #[test]
+    fn play_signing_messages_are_unambiguous() {
+        let event = |campaign_id: &str, creative_hash: &str| PlayEvent {
+            campaign_id: campaign_id.to_string(),
+            creative_hash: creative_hash.to_string(),
+            timestamp: 1,
+            duration_ms: 2,
+            counter: 3,
+            signature: Vec::new(),
+        };
+        assert_ne!(
+            play_signing_message(&user(9), &event("campaign_1", "ab")),
+            play_signing_message(&user(9), &event("campaign_1a", "b")),
+        );
+    }
+
+    fn device_counter() -> u64 {
+        DEVICE_REGISTRY.with(|registry| registry.borrow().get(&user(9))).unwrap().last_counter
+    }
+
+    // Neither a new key nor removing and registering the device again restarts its counter
+    #[test]
+    fn device_counters_survive_key_changes_and_reregistration() {
+        let (campaign_id, provider_id, _, start) = booked_location_with_device(None);
+        let location_id = DEVICE_REGISTRY.with(|registry| registry.borrow().get(&user(9))).unwrap().location_id;
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let play = signed_play(&signing_key(9), &user(9), &campaign_id, start + 1, 7);
+        assert_eq!(report_plays(vec![play.clone()]).unwrap().accepted, 1);
+
+        set_caller(user(2));
+        set_device_key(user(9), signing_key(10).verifying_key().to_bytes().to_vec()).unwrap();
+        assert_eq!(device_counter(), 7);
+        set_caller(user(9));
+        let restarted = signed_play(&signing_key(10), &user(9), &campaign_id, start + 2, 1);
+        assert_eq!(report_plays(vec![restarted]).unwrap().accepted, 0);
+
+        // Back on the first key, the play signed under it cannot be replayed
+        set_caller(user(2));
+        remove_device(user(9)).unwrap();
+        register_device(provider_id, location_id, user(9), signing_key(9).verifying_key().to_bytes().to_vec()).unwrap();
+        assert_eq!(device_counter(), 7);
+        set_caller(user(9));
+        let report = report_plays(vec![play]).unwrap();
+        assert_eq!((report.accepted, report.rejected.len()), (0, 1));
+        let next = signed_play(&signing_key(9), &user(9), &campaign_id, start + 3, 8);
+        assert_eq!(report_plays(vec![next]).unwrap().accepted, 1);
+    }
+
+    // Removing a location retires its devices the same way remove_device does
+    #[test]
+    fn removed_locations_retire_their_devices() {
+        let (campaign_id, provider_id, _, start) = booked_location_with_device(None);
+        let location_id = DEVICE_REGISTRY.with(|registry| registry.borrow().get(&user(9))).unwrap().location_id;
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let play = signed_play(&signing_key(9), &user(9), &campaign_id, start + 1, 7);
+        assert_eq!(report_plays(vec![play]).unwrap().accepted, 1);
+
+        set_time(start + 2 * SLOT_DURATION_NANOS);
+        set_caller(user(2));
+        remove_location(provider_id, location_id).unwrap();
+        assert!(DEVICE_REGISTRY.with(|registry| registry.borrow().get(&user(9))).is_none());
+        assert_eq!(RETIRED_DEVICE_COUNTERS.with(|counters| counters.borrow().get(&user(9))), Some(7));
+    }
+
+    // A campaign linked only to a removed location does not fall back to every other location
+    #[test]
+    fn links_to_a_removed_location_are_deleted() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        set_caller(user(2));
+        let other = add_location(provider_id.clone(), location_input("hall")).unwrap();
+        set_caller(user(1));
+        add_provider(campaign_id.clone(), provider_id.clone(), vec![location_id.clone()]).unwrap();
+
+        set_caller(user(2));
+        remove_location(provider_id.clone(), location_id).unwrap();
+        assert!(campaign_provider_links(&campaign_id).is_empty());
+        set_caller(user(1));
+        let start = next_slot();
+        assert!(matches!(
+            book_location(campaign_id, provider_id, other, start, start + SLOT_DURATION_NANOS),
+            Err(SoulboardError::InvalidInput(_))
+        ));
+    }
+
+    // Slots that started before a booking was cancelled stay in escrow and can still be attested
+    #[test]
+    fn slots_played_before_a_cancellation_can_be_attested() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(None);
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let play = signed_play(&signing_key(9), &user(9), &campaign_id, start + 300_000_000_000, 1);
+        assert_eq!(report_plays(vec![play]).unwrap().accepted, 1);
+
+        set_caller(user(1));
+        cancel_booking(booking_id.clone()).unwrap();
+        assert_eq!(campaign_budget(&campaign_id), tokens(10_000_000 - 100_000));
+
+        set_time(start + SLOT_DURATION_NANOS);
+        set_caller(user(2));
+        assert!(matches!(
+            attest_delivery(booking_id.clone(), vec![start + SLOT_DURATION_NANOS]),
+            Err(SoulboardError::Conflict(_))
+        ));
+        assert_eq!(attest_delivery(booking_id, vec![start]).unwrap(), tokens(100_000));
+        assert_eq!(provider_earnings(&provider_id), tokens(100_000));
+    }
+
+    fn campaign_status(campaign_id: &str) -> CampaignStatus {
+        CAMPAIGN_REGISTRY.with(|registry| registry.borrow().get(&campaign_id.to_string())).unwrap().status
+    }
+
+    // The hourly tick reads only the campaigns that are due, and a completed campaign gives back
+    // the slots it booked past its end
+    #[test]
+    fn campaigns_advance_through_the_lifecycle_index() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        let start = next_slot();
+        book_location(campaign_id.clone(), provider_id, location_id, start, start + 3 * SLOT_DURATION_NANOS).unwrap();
+        schedule_campaign(campaign_id.clone(), start, start + SLOT_DURATION_NANOS).unwrap();
+
+        // Stored before the lifecycle index existed, so only the upgrade files it
+        let mut legacy = test_campaign("campaign_legacy", user(1));
+        legacy.status = CampaignStatus::Active;
+        legacy.end_time = Some(start + SLOT_DURATION_NANOS);
+        CAMPAIGN_REGISTRY.with(|registry| registry.borrow_mut().insert(legacy.id.clone(), legacy));
+        CAMPAIGN_LIFECYCLE_INDEX.with(|index| index.borrow_mut().clear_new());
+        CAMPAIGN_BOOKINGS.with(|index| index.borrow_mut().clear_new());
+        upgrade();
+
+        set_time(start);
+        advance_campaign_lifecycles();
+        assert!(campaign_status(&campaign_id) == CampaignStatus::Active);
+
+        set_time(start + SLOT_DURATION_NANOS);
+        advance_campaign_lifecycles();
+        assert!(campaign_status(&campaign_id) == CampaignStatus::Completed);
+        assert!(campaign_status("campaign_legacy") == CampaignStatus::Completed);
+        assert_eq!(campaign_budget(&campaign_id), tokens(10_000_000 - 100_000));
+
+        set_time(start + SLOT_DURATION_NANOS + ARCHIVE_DELAY_NANOS);
+        advance_campaign_lifecycles();
+        assert!(campaign_status(&campaign_id) == CampaignStatus::Archived);
+        assert!(CAMPAIGN_LIFECYCLE_INDEX.with(|index| index.borrow().is_empty()));
+    }
+
+    // Slots still waiting for attestation are settled when the campaign closes: the played one is
+    // paid to the provider and the other one refunded before the budget goes back to the owner
+    #[test]
+    fn closing_settles_slots_awaiting_attestation() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(None);
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let play = signed_play(&signing_key(9), &user(9), &campaign_id, start + 300_000_000_000, 1);
+        assert_eq!(report_plays(vec![play]).unwrap().accepted, 1);
+
+        set_time(start + 2 * SLOT_DURATION_NANOS);
+        set_caller(user(1));
+        run(close_campaign(campaign_id.clone())).unwrap();
+        assert_eq!(provider_earnings(&provider_id), tokens(100_000));
+        let escrow = ESCROW_REGISTRY.with(|registry| registry.borrow().get(&booking_id)).unwrap();
+        assert_eq!((escrow.escrowed, escrow.released, escrow.refunded), (tokens(0), tokens(100_000), tokens(100_000)));
+        let refunded = tokens(10_000_000 - 100_000 - 10_000);
+        assert_eq!(executed_transfers(), vec![(principal_to_account(user(1)), refunded)]);
+    }
+
+    fn location_status(provider_id: &str, location_id: &str) -> LocationStatus {
+        let provider = PROVIDER_REGISTRY.with(|registry| registry.borrow().get(&provider_id.to_string())).unwrap();
+        provider.locations.into_iter().find(|location| location.id == location_id).unwrap().status
+    }
+
+    // The slot tick refreshes the locations whose bookings start or end, and a cancellation
+    // refreshes its own location right away
+    #[test]
+    fn location_statuses_follow_booking_boundaries() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        let start = next_slot();
+        book_location(campaign_id.clone(), provider_id.clone(), location_id.clone(), start, start + 2 * SLOT_DURATION_NANOS).unwrap();
+        let later = start + 3 * SLOT_DURATION_NANOS;
+        let second = book_location(campaign_id, provider_id.clone(), location_id.clone(), later, later + SLOT_DURATION_NANOS).unwrap();
+        assert!(location_status(&provider_id, &location_id) == LocationStatus::Active);
+
+        set_time(start);
+        run_slot_tick();
+        assert!(location_status(&provider_id, &location_id) == LocationStatus::Booked);
+        set_time(start + 2 * SLOT_DURATION_NANOS);
+        run_slot_tick();
+        assert!(location_status(&provider_id, &location_id) == LocationStatus::Active);
+
+        set_time(later);
+        run_slot_tick();
+        assert!(location_status(&provider_id, &location_id) == LocationStatus::Booked);
+        cancel_booking(second).unwrap();
+        assert!(location_status(&provider_id, &location_id) == LocationStatus::Active);
+        assert!(BOOKING_BOUNDARIES.with(|index| index.borrow().is_empty()));
+    }
+
+    // Reports one signed play per counter in the booking's first slot and returns the device
+    // clock to a time inside that slot
+    fn report_signed_plays(campaign_id: &str, start: u64, counters: std::ops::RangeInclusive<u64>) {
+        set_time(start + 600_000_000_000);
+        set_caller(user(9));
+        let plays = counters.map(|counter| signed_play(&signing_key(9), &user(9), campaign_id, start + counter, counter)).collect();
+        assert!(report_plays(plays).unwrap().rejected.is_empty());
+    }
+
+    fn reprice_location(provider_id: &str, location_id: &str, base_fees: u64, cpm_rate: Option<u64>) {
+        set_caller(user(2));
+        let input = LocationInput { base_fees: tokens(base_fees), cpm_rate: cpm_rate.map(tokens), ..location_input("lobby") };
+        update_location(provider_id.to_string(), location_id.to_string(), input).unwrap();
+    }
+
+    fn bill_all(batch_size: u64) {
+        while run_billing_batch(batch_size) > 0 {}
+    }
+
+    // Billing the same play log in batches of any size gives the same charges, and running the
+    // engine again once it caught up charges nothing more
+    #[test]
+    fn billing_replays_to_the_same_charges() {
+        let bill_plays_in_batches_of = |batch_size: u64| {
+            std::thread::spawn(move || {
+                let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(Some(1_500));
+                report_signed_plays(&campaign_id, start, 1..=7);
+                bill_all(batch_size);
+                let charges = (campaign_budget(&campaign_id), provider_earnings(&provider_id));
+                assert_eq!(run_billing_batch(BILLING_BATCH_SIZE), 0);
+                assert_eq!((campaign_budget(&campaign_id), provider_earnings(&provider_id)), charges);
+                let location_id = booking(&booking_id).location_id;
+                let key = format!("{}:{}", campaign_id, location_key(&provider_id, &location_id));
+                let account = BILLING_ACCOUNTS.with(|accounts| accounts.borrow().get(&key)).unwrap();
+                (charges, account.impressions, account.charged, account.carry)
+            })
+            .join()
+            .unwrap()
+        };
+
+        let outcome = bill_plays_in_batches_of(BILLING_BATCH_SIZE);
+        // 7 impressions at 1.5 units each: 10 units charged, half a unit carried
+        assert_eq!(outcome, ((tokens(10_000_000 - 10), tokens(10)), 7, tokens(10), tokens(500)));
+        assert_eq!(bill_plays_in_batches_of(1), outcome);
+        assert_eq!(bill_plays_in_batches_of(3), outcome);
+    }
+
+    #[test]
+    fn impressions_are_billed_at_the_booked_rate() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(Some(10_000));
+        reprice_location(&provider_id, &booking(&booking_id).location_id, 100_000, Some(1_000_000));
+        report_signed_plays(&campaign_id, start, 1..=2);
+        bill_all(BILLING_BATCH_SIZE);
+        assert_eq!(campaign_budget(&campaign_id), tokens(10_000_000 - 20));
+        assert_eq!(provider_earnings(&provider_id), tokens(20));
+    }
+
+    // A booking paid per slot is not charged per impression after the location switches to CPM
+    #[test]
+    fn slot_paid_bookings_are_not_billed_per_impression() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(None);
+        reprice_location(&provider_id, &booking(&booking_id).location_id, 100_000, Some(1_000_000));
+        report_signed_plays(&campaign_id, start, 1..=2);
+        bill_all(BILLING_BATCH_SIZE);
+        assert_eq!(campaign_budget(&campaign_id), tokens(10_000_000 - 2 * 100_000));
+        assert_eq!(provider_earnings(&provider_id), tokens(0));
+    }
+
+    // A close bills at most one batch; it is refused until every earlier play is billed
+    #[test]
+    fn closing_waits_for_billing_to_catch_up() {
+        let (campaign_id, provider_id, booking_id, start) = booked_location_with_device(Some(1_000));
+        let play = PlayRecord {
+            device: user(9),
+            provider_id: provider_id.clone(),
+            location_id: booking(&booking_id).location_id,
+            booking_id,
+            campaign_id: campaign_id.clone(),
+            creative_hash: "sha256:creative".to_string(),
+            timestamp: start,
+            duration_ms: 15_000,
+            reported_at: start,
+            counter: None,
+            signature: None,
+        };
+        PLAY_LOG.with(|log| {
+            let log = log.borrow();
+            for _ in 0..BILLING_BATCH_SIZE + 1 {
+                log.append(&play).unwrap();
+            }
+        });
+
+        set_time(start + 2 * SLOT_DURATION_NANOS);
+        set_caller(user(1));
+        assert!(matches!(run(close_campaign(campaign_id.clone())), Err(SoulboardError::Conflict(_))));
+        assert_eq!(get_billing_state().next_play, BILLING_BATCH_SIZE);
+        assert!(run(close_campaign(campaign_id.clone())).is_ok());
+        assert_eq!(provider_earnings(&provider_id), tokens(BILLING_BATCH_SIZE + 1));
+        let refunded = tokens(10_000_000 - (BILLING_BATCH_SIZE + 1) - 10_000);
+        assert_eq!(executed_transfers(), vec![(principal_to_account(user(1)), refunded)]);
+    }
+
+    // Two providers whose locations were registered under the same ID before IDs were generated
+    fn providers_sharing_a_location_id() -> (String, String) {
+        setup();
+        let mut provider_ids = Vec::new();
+        for owner in [2, 3] {
+            set_caller(user(owner));
+            let provider_id = register_provider("Screens".to_string(), vec![location_input("lobby")]).unwrap();
+            PROVIDER_REGISTRY.with(|registry| {
+                let mut registry = registry.borrow_mut();
+                let mut provider = registry.get(&provider_id).unwrap();
+                provider.locations[0].id = "lobby".to_string();
+                provider.locations[0].coordinates = Some(Coordinates { latitude: 52.52, longitude: 13.40 + owner as f64 / 1000.0 });
+                registry.insert(provider_id.clone(), provider);
+            });
+            provider_ids.push(provider_id);
+        }
+        LOCATION_INDEX.with(|index| index.borrow_mut().clear_new());
+        GEO_INDEX.with(|index| index.borrow_mut().clear_new());
+        upgrade();
+        (provider_ids[0].clone(), provider_ids[1].clone())
+    }
+
+    #[test]
+    fn shared_location_ids_are_indexed_per_provider() {
+        let (first, second) = providers_sharing_a_location_id();
+        let page = get_all_locations(LocationFilter::default(), PageRequest { cursor: None, limit: Some(1) }).unwrap();
+        assert_eq!(page.items[0].provider_id, first);
+        let page = get_all_locations(LocationFilter::default(), PageRequest { cursor: page.next_cursor, limit: Some(1) }).unwrap();
+        assert_eq!(page.items[0].provider_id, second);
+        assert!(page.next_cursor.is_none());
+        assert_eq!(get_location("lobby".to_string()).unwrap().provider_id, first);
+
+        // Removing one provider's location leaves the other one's entry in place
+        set_caller(user(2));
+        remove_location(first, "lobby".to_string()).unwrap();
+        assert_eq!(get_location("lobby".to_string()).unwrap().provider_id, second);
+        let all = get_all_locations(LocationFilter::default(), PageRequest::default()).unwrap();
+        assert_eq!(all.items.len(), 1);
+    }
+
+    #[test]
+    fn shared_location_ids_are_found_nearby_per_provider() {
+        let (first, second) = providers_sharing_a_location_id();
+        let nearby = |providers: &[&String]| {
+            let found = search_locations_near(52.52, 13.40, 1_000.0).unwrap();
+            let found: Vec<String> = found.into_iter().map(|nearby| nearby.location.provider_id).collect();
+            assert_eq!(found, providers.iter().map(|id| id.to_string()).collect::<Vec<_>>());
+        };
+        nearby(&[&first, &second]);
+
+        set_caller(user(2));
+        remove_location(first, "lobby".to_string()).unwrap();
+        nearby(&[&second]);
+    }
+
+    #[test]
+    fn location_cursors_round_trip() {
+        for key in [PairKey("a:b".to_string(), "c".to_string()), PairKey(String::new(), "x:1".to_string())] {
+            assert!(key.to_string().parse::<PairKey<String, String>>() == Ok(key));
+        }
+        assert!("3:ab".parse::<PairKey<String, String>>().is_err());
+        assert!("lobby".parse::<PairKey<String, String>>().is_err());
+    }
+
+    fn provider_ids(page: &Page<Provider>) -> Vec<String> {
+        page.items.iter().map(|provider| provider.id.clone()).collect()
+    }
+
+    #[test]
+    fn provider_listings_page_from_the_cursor() {
+        setup();
+        set_caller(user(2));
+        let with_location = register_provider("Screens".to_string(), vec![location_input("lobby")]).unwrap();
+        let without_locations = register_provider("Empty".to_string(), Vec::new()).unwrap();
+        let third = register_provider("More".to_string(), vec![location_input("hall")]).unwrap();
+
+        let first_page = get_all_providers(LocationFilter::default(), PageRequest { cursor: None, limit: Some(2) });
+        assert_eq!(provider_ids(&first_page), vec![with_location.clone(), without_locations.clone()]);
+        assert_eq!(first_page.total, Some(3));
+        let last_page = get_all_providers(LocationFilter::default(), PageRequest { cursor: first_page.next_cursor, limit: Some(2) });
+        assert_eq!(provider_ids(&last_page), vec![third.clone()]);
+        assert!(last_page.next_cursor.is_none());
+
+        // A location field filters out providers without a matching location
+        let active = LocationFilter { status: Some(LocationStatus::Active), ..LocationFilter::default() };
+        let filtered = get_all_providers(active, PageRequest::default());
+        assert_eq!(provider_ids(&filtered), vec![with_location, third]);
+        assert_eq!(filtered.total, Some(2));
+
+        let by_provider = LocationFilter { provider_id: Some(without_locations.clone()), ..LocationFilter::default() };
+        assert_eq!(provider_ids(&get_all_providers(by_provider, PageRequest::default())), vec![without_locations]);
+    }
+
+    #[test]
+    fn owner_listings_read_the_owner_indexes() {
+        setup();
+        // Stored before the owner indexes existed, so only the upgrade indexes it
+        CAMPAIGN_REGISTRY.with(|registry| {
+            registry.borrow_mut().insert("campaign_legacy".to_string(), test_campaign("campaign_legacy", user(1)))
+        });
+        upgrade();
+        set_caller(user(1));
+        let first = create_campaign("Spring".to_string(), String::new(), None, None, None).unwrap();
+        let second = create_campaign("Summer".to_string(), String::new(), None, None, None).unwrap();
+        set_caller(user(3));
+        create_campaign("Autumn".to_string(), String::new(), None, None, None).unwrap();
+        CAMPAIGN_REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            let mut campaign = registry.get(&first).unwrap();
+            campaign.status = CampaignStatus::Paused;
+            registry.insert(first.clone(), campaign);
+        });
+
+        set_caller(user(1));
+        let campaign_ids = |page: &Page<Campaign>| page.items.iter().map(|campaign| campaign.id.clone()).collect::<Vec<_>>();
+        let page = get_my_campaigns(None, PageRequest { cursor: None, limit: Some(2) });
+        assert_eq!(campaign_ids(&page), vec![first, second.clone()]);
+        assert_eq!(page.total, Some(3));
+        let page = get_my_campaigns(None, PageRequest { cursor: page.next_cursor, limit: Some(2) });
+        assert_eq!(campaign_ids(&page), vec!["campaign_legacy".to_string()]);
+        assert!(page.next_cursor.is_none());
+        let drafts = get_my_campaigns(Some(CampaignStatus::Draft), PageRequest::default());
+        assert_eq!(campaign_ids(&drafts), vec![second, "campaign_legacy".to_string()]);
+        assert_eq!(drafts.total, Some(2));
+
+        set_caller(user(2));
+        let screens = register_provider("Screens".to_string(), Vec::new()).unwrap();
+        let more = register_provider("More".to_string(), Vec::new()).unwrap();
+        // Saving a provider again does not count it twice
+        update_provider(screens.clone(), "Screens and more".to_string()).unwrap();
+        set_caller(user(3));
+        register_provider("Other".to_string(), Vec::new()).unwrap();
+        set_caller(user(2));
+        let providers = get_my_providers(PageRequest::default());
+        assert_eq!(provider_ids(&providers), vec![screens, more]);
+        assert_eq!(providers.total, Some(2));
+    }
+
+    fn point(latitude: f64, longitude: f64) -> Coordinates {
+        Coordinates { latitude, longitude }
+    }
+
+    // Fixtures from the reference geohash implementation
+    #[test]
+    fn geohashes_match_reference_values() {
+        assert_eq!(geohash_encode(&point(57.64911, 10.40744), 11), "u4pruydqqvj");
+        assert_eq!(geohash_encode(&point(48.8566, 2.3522), 9), "u09tvw0f6");
+        assert_eq!(geohash_encode(&point(-33.8688, 151.2093), 7), "r3gx2f7");
+        assert_eq!(geohash_encode(&point(0.0, 0.0), 5), "s0000");
+        assert_eq!(geohash_encode(&point(-90.0, -180.0), 3), "000");
+    }
+
+    #[test]
+    fn distances_follow_the_great_circle() {
+        let paris = point(48.8566, 2.3522);
+        let london = point(51.5074, -0.1278);
+        assert!((distance_m(&paris, &london) - 343_556.5).abs() < 1.0);
+        assert_eq!(distance_m(&paris, &paris), 0.0);
+        // Across the antimeridian the short way round is taken
+        assert!((distance_m(&point(0.0, 179.9), &point(0.0, -179.9)) - 22_239.0).abs() < 1.0);
+        assert!((distance_m(&point(0.0, 0.0), &point(0.0, 180.0)) - std::f64::consts::PI * EARTH_RADIUS_M).abs() < 1.0);
+    }
+
+    #[test]
+    fn radius_boxes_cover_the_circle() {
+        let center = point(48.8566, 2.3522);
+        let boxes = radius_boxes(&center, 10_000.0);
+        assert_eq!(boxes.len(), 1);
+        // Points 10 km north, south, east and west of the center lie inside
+        let angle = (10_000.0 / EARTH_RADIUS_M).to_degrees();
+        let dlon = angle / center.latitude.to_radians().cos();
+        for edge in [point(48.8566 + angle * 0.999, 2.3522), point(48.8566 - angle * 0.999, 2.3522), point(48.8566, 2.3522 + dlon * 0.999), point(48.8566, 2.3522 - dlon * 0.999)] {
+            assert!(boxes[0].contains(&edge));
+        }
+
+        // A circle around a pole spans every meridian
+        let polar = radius_boxes(&point(89.95, 10.0), 10_000.0);
+        assert_eq!(polar.len(), 1);
+        assert_eq!((polar[0].west, polar[0].east, polar[0].north), (-180.0, 180.0, 90.0));
+    }
+
+    #[test]
+    fn areas_crossing_the_antimeridian_are_split() {
+        let boxes = radius_boxes(&point(0.0, 179.95), 20_000.0);
+        assert_eq!(boxes.len(), 2);
+        assert!(boxes.iter().any(|area| area.contains(&point(0.0, 179.99))));
+        assert!(boxes.iter().any(|area| area.contains(&point(0.0, -179.99))));
+        assert!(!boxes.iter().any(|area| area.contains(&point(0.0, 0.0))));
+
+        let split = split_at_antimeridian(-10.0, 170.0, 10.0, -170.0);
+        assert_eq!(split.len(), 2);
+        assert_eq!((split[0].west, split[0].east, split[1].west, split[1].east), (170.0, 180.0, -180.0, -170.0));
+        assert_eq!(split_at_antimeridian(-10.0, -10.0, 10.0, 10.0).len(), 1);
+    }
+
+    #[test]
+    fn geohash_cells_cover_their_area() {
+        let area = GeoBox { south: 48.80, west: 2.25, north: 48.90, east: 2.45 };
+        let cells = geohash_cells(&area);
+        assert!(cells.len() as u64 <= MAX_GEO_CELLS);
+        for inside in [point(48.80, 2.25), point(48.8566, 2.3522), point(48.90, 2.45)] {
+            let hash = geohash_encode(&inside, GEOHASH_PRECISION);
+            assert!(cells.iter().any(|cell| hash.starts_with(cell.as_str())));
+        }
+    }
+
+    #[test]
+    fn nearby_search_finds_locations_across_the_antimeridian() {
+        setup();
+        set_caller(user(2));
+        let at = |name: &str, latitude: f64, longitude: f64| LocationInput {
+            coordinates: Some(point(latitude, longitude)),
+            ..location_input(name)
+        };
+        register_provider(
+            "Pacific".to_string(),
+            vec![at("east", 0.0, 179.99), at("west", 0.0, -179.99), at("far", 0.0, 170.0)],
+        )
+        .unwrap();
+
+        let found = search_locations_near(0.0, 179.95, 20_000.0).unwrap();
+        let names: Vec<&str> = found.iter().map(|nearby| nearby.location.location.name.as_str()).collect();
+        assert_eq!(names, vec!["east", "west"]);
+        let in_box = search_locations_in_box(-1.0, 179.0, 1.0, -179.0).unwrap();
+        assert_eq!(in_box.len(), 2);
+    }
+
+    fn search_names(page: &Page<SearchResult>) -> Vec<(String, u64)> {
+        page.items
+            .iter()
+            .map(|result| match &result.hit {
+                SearchHit::Provider(provider) => (provider.name.clone(), result.score),
+                SearchHit::Location(view) => (view.location.name.clone(), result.score),
+            })
+            .collect()
+    }
+
+    // Queries are case folded, match the start of indexed words, and rank exact matches in
+    // heavier fields first
+    #[test]
+    fn search_folds_case_matches_prefixes_and_ranks_by_weight() {
+        setup();
+        set_caller(user(2));
+        register_provider(
+            "Screens".to_string(),
+            vec![
+                location_input("Lobby"),
+                LocationInput { tags: Some(vec!["Lobbyist".to_string()]), ..location_input("Hall") },
+                LocationInput { address: Some("1 Lobby Road".to_string()), ..location_input("Corner") },
+            ],
+        )
+        .unwrap();
+        let query = |text: &str, limit: Option<u64>, cursor: Option<String>| {
+            search(text.to_string(), SearchFilter::default(), PageRequest { cursor, limit }).unwrap()
+        };
+
+        let exact = query("LOBBY", None, None);
+        assert_eq!(search_names(&exact), vec![("Lobby".to_string(), 8), ("Corner".to_string(), 4), ("Hall".to_string(), 3)]);
+        assert_eq!(exact.total, Some(3));
+
+        let prefix = query("lob", None, None);
+        assert_eq!(search_names(&prefix), vec![("Lobby".to_string(), 4), ("Hall".to_string(), 3), ("Corner".to_string(), 2)]);
+
+        // Every query word has to match
+        assert_eq!(search_names(&query("lobby ro", None, None)), vec![("Corner".to_string(), 6)]);
+        assert_eq!(search_names(&query("scr", None, None))[0], ("Screens".to_string(), 4));
+
+        // Pages walk the same ranking
+        let first = query("lobby", Some(2), None);
+        assert_eq!(search_names(&first).len(), 2);
+        let last = query("lobby", Some(2), first.next_cursor);
+        assert_eq!(search_names(&last), vec![("Hall".to_string(), 3)]);
+        assert!(last.next_cursor.is_none());
+    }
+
+    // Indexes found empty by an upgrade are filled by the rebuild passes, a batch per timer run
+    #[test]
+    fn rebuild_passes_fill_indexes_in_batches() {
+        setup();
+        set_caller(user(2));
+        for _ in 0..MIGRATION_BATCH_SIZE + 50 {
+            register_provider("Screens".to_string(), vec![location_input("lobby")]).unwrap();
+        }
+        LOCATION_INDEX.with(|index| index.borrow_mut().clear_new());
+        SEARCH_INDEX.with(|index| index.borrow_mut().clear_new());
+
+        plan_rebuilds();
+        assert!(pending_rebuilds().location_index && pending_rebuilds().search_index);
+        MIGRATION_CURSOR.with(|cursor| *cursor.borrow_mut() = (0, None));
+        let mut provider_batches = 0;
+        while migrate_next_batch() {
+            if get_migration_status().current_registry.as_deref() == Some(REBUILD_PASSES[1]) {
+                provider_batches += 1;
+            }
+        }
+
+        assert_eq!(provider_batches, 2);
+        assert_eq!(LOCATION_INDEX.with(|index| index.borrow().len()), MIGRATION_BATCH_SIZE as u64 + 50);
+        let found = search("lobby".to_string(), SearchFilter::default(), PageRequest::default()).unwrap();
+        assert_eq!(found.total, Some(MIGRATION_BATCH_SIZE as u64 + 50));
+        assert!(!pending_rebuilds().location_index && !pending_rebuilds().search_index);
+        assert!(get_migration_status().finished_at.is_some());
+    }
+
+    // Balance of an account recomputed from the transaction log
+    fn log_balance(account: TransactionAccount) -> NumTokens {
+        let key = account_index_key(&account);
+        let ids: Vec<u64> = TRANSACTION_INDEX.with(|index| {
+            index.borrow().range(PairKey(key.clone(), 0)..).take_while(|entry| entry.key().0 == key).map(|entry| entry.key().1).collect()
+        });
+        let records: Vec<TransactionRecord> = ids.into_iter().filter_map(|id| TRANSACTION_LOG.with(|log| log.borrow().get(id))).collect();
+        let incoming = records.iter().filter(|record| account_index_key(&record.to) == key).fold(tokens(0), |sum, record| sum + record.amount.clone());
+        let outgoing = records.iter().filter(|record| account_index_key(&record.from) == key).fold(tokens(0), |sum, record| sum + record.amount.clone());
+        incoming - outgoing
+    }
+
+    // Balances that move between the upgrade and their rebuild pass are opened at what they held
+    // before the move, so the log is not off by the movement
+    #[test]
+    fn opening_balances_account_for_movements_during_the_rebuild() {
+        let (campaign_id, provider_id, location_id) = booking_fixture(None);
+        let earning_provider = provider_with_earnings(40_000);
+        assert_eq!(TRANSACTION_LOG.with(|log| log.borrow().len()), 0);
+
+        plan_rebuilds();
+        assert!(pending_rebuilds().opening_balances);
+        let start = next_slot();
+        let booking_id = book_location(campaign_id.clone(), provider_id, location_id, start, start + SLOT_DURATION_NANOS).unwrap();
+        upgrade();
+
+        assert_eq!(log_balance(TransactionAccount::Campaign(campaign_id.clone())), campaign_budget(&campaign_id));
+        assert_eq!(log_balance(TransactionAccount::Escrow(booking_id)), tokens(100_000));
+        assert_eq!(log_balance(TransactionAccount::Provider(earning_provider)), tokens(40_000));
+
+        // Running the passes again opens nothing twice
+        let logged = TRANSACTION_LOG.with(|log| log.borrow().len());
+        PENDING_REBUILDS.with(|cell| cell.borrow_mut().set(PendingRebuilds { opening_balances: true, ..Default::default() }));
+        upgrade();
+        assert_eq!(TRANSACTION_LOG.with(|log| log.borrow().len()), logged);
+    }
+
+    #[test]
+    fn closing_returns_the_refund_receipt() {
+        setup();
+        let campaign_id = campaign_with_budget(300_000);
+        let receipt = run(close_campaign(campaign_id.clone())).unwrap().unwrap();
+        assert!(matches!(receipt.kind, ReceiptKind::CampaignCloseRefund));
+        assert_eq!((receipt.amount.clone(), receipt.fee.clone(), receipt.balance.clone()), (tokens(290_000), tokens(10_000), tokens(0)));
+        assert_eq!(receipt.campaign_id.as_deref(), Some(campaign_id.as_str()));
+
+        let mine = get_my_receipts(PageRequest::default()).unwrap();
+        assert_eq!(mine.items.iter().map(|receipt| receipt.transaction_id).collect::<Vec<_>>(), vec![receipt.transaction_id]);
+        set_caller(user(2));
+        assert!(get_my_receipts(PageRequest::default()).unwrap().items.is_empty());
+
+        // A budget that does not cover the ledger fee closes without a refund
+        let dust = campaign_with_budget(10_000);
+        assert!(run(close_campaign(dust)).unwrap().is_none());
+    }
+
+    fn test_receipt(transaction_id: u64, owner: Principal) -> Receipt {
+        Receipt {
+            transaction_id,
+            kind: ReceiptKind::CampaignFunding,
+            owner,
+            campaign_id: None,
+            provider_id: None,
+            token: "ICP".to_string(),
+            amount: tokens(1),
+            fee: tokens(0),
+            balance: tokens(1),
+            block_index: None,
+            timestamp: 0,
+        }
+    }
+
+    // Receipts stored before the owner index existed are indexed by the rebuild passes
+    #[test]
+    fn receipts_are_paged_per_owner() {
+        setup();
+        RECEIPTS.with(|receipts| {
+            let mut receipts = receipts.borrow_mut();
+            for transaction_id in 0..7 {
+                let owner = if transaction_id % 2 == 0 { user(1) } else { user(2) };
+                receipts.insert(transaction_id, test_receipt(transaction_id, owner));
+            }
+        });
+        upgrade();
+
+        set_caller(user(1));
+        let first = get_my_receipts(PageRequest { cursor: None, limit: Some(3) }).unwrap();
+        let ids = |page: &Page<Receipt>| page.items.iter().map(|receipt| receipt.transaction_id).collect::<Vec<_>>();
+        assert_eq!(ids(&first), vec![0, 2, 4]);
+        let rest = get_my_receipts(PageRequest { cursor: first.next_cursor, limit: Some(3) }).unwrap();
+        assert_eq!(ids(&rest), vec![6]);
+        assert!(rest.next_cursor.is_none());
+        set_caller(user(2));
+        assert_eq!(ids(&get_my_receipts(PageRequest::default()).unwrap()), vec![1, 3, 5]);
+    }
+
+    // A payout in flight holds the amount and its fee, as logged when it was journaled
+    #[test]
+    fn payouts_in_flight_hold_amount_and_fee() {
+        setup();
+        let provider_id = provider_with_earnings(1_000_000);
+        hold_ledger(true);
+        let mut withdrawal = pin!(withdraw_provider_earnings(provider_id, "ICP".to_string(), tokens(500_000)));
+        assert!(poll_once(withdrawal.as_mut()).is_pending());
+        let transfer_id = PENDING_TRANSFERS.with(|journal| journal.borrow().iter().next().map(|entry| *entry.key())).unwrap();
+
+        set_caller(user(99));
+        let check = check_account_balance(TransactionAccount::Transfer(transfer_id), "ICP".to_string()).unwrap();
+        assert_eq!(check.stored_balance, tokens(510_000));
+        assert!(check.consistent);
+        hold_ledger(false);
+        assert!(matches!(poll_once(withdrawal.as_mut()), Poll::Ready(Ok(_))));
+    }
+
+    #[test]
+    fn closed_campaigns_take_no_deposits() {
+        setup();
+        let campaign_id = campaign_with_budget(0);
+        CAMPAIGN_REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            let mut campaign = registry.get(&campaign_id).unwrap();
+            campaign.status = CampaignStatus::Cancelled;
+            registry.insert(campaign_id.clone(), campaign);
+        });
+        assert!(matches!(run(notify_campaign_deposit(campaign_id)), Err(SoulboardError::Conflict(_))));
+    }
+}